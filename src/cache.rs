@@ -0,0 +1,261 @@
+use crate::cache_dir;
+use anyhow::{Context, Result};
+use camino::{Utf8Path, Utf8PathBuf};
+use sha2::{Digest, Sha256};
+use std::fmt::Write;
+use std::fs::{File, Permissions};
+use std::io::ErrorKind;
+use std::os::unix::fs::PermissionsExt;
+use std::time::SystemTime;
+use tempfile::{NamedTempFile, TempPath};
+
+// Blobs are stored by the SHA-256 of their contents in `blobs/`. Files in `keys/` map an artefact's
+// URL to the hash of the blob last downloaded from it and the ETag it was sent with, so that it's
+// only downloaded again if the server says it's changed, and byte-identical artefacts from
+// different runs share a single blob. Artefacts sent without an ETag aren't cached, as there'd be
+// no way to tell whether they've changed.
+#[derive(Debug)]
+pub(crate) struct ArtefactCache {
+    dir: Utf8PathBuf,
+}
+
+impl ArtefactCache {
+    pub(crate) fn open() -> Result<ArtefactCache> {
+        let dir = cache_dir()?.join("artefacts");
+        for sub in ["blobs", "keys", "tmp"] {
+            std::fs::create_dir_all(dir.join(sub))
+                .with_context(|| format!("failed to create {}", dir.join(sub)))?;
+        }
+        Ok(ArtefactCache { dir })
+    }
+
+    pub(crate) fn dir(&self) -> &Utf8Path {
+        &self.dir
+    }
+
+    // Returns the ETag and blob last cached for `url`, if any.
+    pub(crate) fn lookup(&self, url: &str) -> Result<Option<(String, Utf8PathBuf)>> {
+        let key = match std::fs::read_to_string(self.key_path(url)) {
+            Ok(key) => key,
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err.into()),
+        };
+        let mut lines = key.lines();
+        let (Some(hash), Some(etag)) = (lines.next(), lines.next()) else {
+            return Ok(None);
+        };
+        let blob = self.dir.join("blobs").join(hash);
+        match File::options().write(true).open(&blob) {
+            Ok(file) => {
+                // Bump the mtime so that `prune` evicts the least recently used blobs first.
+                file.set_modified(SystemTime::now())?;
+                Ok(Some((etag.to_owned(), blob)))
+            }
+            Err(err) if err.kind() == ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    pub(crate) fn temp_file(&self) -> Result<NamedTempFile> {
+        Ok(NamedTempFile::new_in(self.dir.join("tmp"))?)
+    }
+
+    pub(crate) fn insert(
+        &self,
+        url: &str,
+        etag: &str,
+        temp: TempPath,
+        hasher: Sha256,
+    ) -> Result<Utf8PathBuf> {
+        let hash = hex(&hasher.finalize());
+        let blob = self.dir.join("blobs").join(&hash);
+        // Temporary files are only readable by us, but the build user reads artefacts in /input
+        // (which are often hard links to the blob).
+        std::fs::set_permissions(&temp, Permissions::from_mode(0o644))?;
+        temp.persist(&blob)?;
+
+        let mut key = self.temp_file()?;
+        std::io::Write::write_all(&mut key, format!("{}\n{}\n", hash, etag).as_bytes())?;
+        key.persist(self.key_path(url))?;
+        Ok(blob)
+    }
+
+    // Deletes the least recently used blobs until the cache is no larger than `max_size` bytes.
+    // Returns the number of blobs removed and the size of the cache afterwards.
+    pub(crate) fn prune(&self, max_size: u64) -> Result<(usize, u64)> {
+        let mut blobs = Vec::new();
+        let mut total = 0;
+        for entry in self.dir.join("blobs").read_dir_utf8()? {
+            let entry = entry?;
+            let metadata = entry.metadata()?;
+            total += metadata.len();
+            blobs.push((metadata.modified()?, metadata.len(), entry.into_path()));
+        }
+        blobs.sort_unstable();
+
+        let mut removed = 0;
+        for (_, len, path) in blobs {
+            if total <= max_size {
+                break;
+            }
+            std::fs::remove_file(path)?;
+            total -= len;
+            removed += 1;
+        }
+
+        // Key files pointing at removed blobs are treated as misses by `lookup`, but clean them
+        // up so they don't accumulate.
+        for entry in self.dir.join("keys").read_dir_utf8()? {
+            let entry = entry?;
+            let key = std::fs::read_to_string(entry.path())?;
+            let hash = key.lines().next().unwrap_or_default();
+            if !self.dir.join("blobs").join(hash).exists() {
+                std::fs::remove_file(entry.path())?;
+            }
+        }
+
+        Ok((removed, total))
+    }
+
+    fn key_path(&self, url: &str) -> Utf8PathBuf {
+        self.dir
+            .join("keys")
+            .join(hex(&Sha256::digest(format!("url:{}", url))))
+    }
+}
+
+// Hard links are cheapest, but `/input` datasets are separate filesystems from the cache, so this
+// usually falls back to `std::fs::copy` (which reflinks where the OS and filesystem support it).
+pub(crate) fn link_or_copy(src: &Utf8Path, dest: &Utf8Path) -> Result<()> {
+    if std::fs::hard_link(src, dest).is_err() {
+        let parent = dest
+            .parent()
+            .expect("destination path must have parent directory");
+        let temp = NamedTempFile::new_in(parent)?.into_temp_path();
+        std::fs::copy(src, &temp)?;
+        temp.persist(dest)?;
+    }
+    // Blobs cached by older versions are only readable by us.
+    std::fs::set_permissions(dest, Permissions::from_mode(0o644))?;
+    Ok(())
+}
+
+// Image tarballs are extracted to a directory named for the tarball's path, size and mtime, so
+// that replacing the tarball results in a fresh extraction.
+pub(crate) fn image_dir(tarball: &Utf8Path) -> Result<Utf8PathBuf> {
+    let metadata = std::fs::metadata(tarball)?;
+    let modified = metadata
+        .modified()?
+        .duration_since(SystemTime::UNIX_EPOCH)?
+        .as_nanos();
+    let key = format!("{}\n{}\n{}", tarball, metadata.len(), modified);
+    Ok(cache_dir()?.join("images").join(hex(&Sha256::digest(key))))
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().fold(String::new(), |mut s, b| {
+        write!(s, "{:02x}", b).unwrap();
+        s
+    })
+}
+
+#[cfg(test)]
+#[test]
+fn test_insert_lookup_prune() {
+    use std::time::Duration;
+
+    let tempdir = tempfile::tempdir().unwrap();
+    let dir = Utf8PathBuf::try_from(tempdir.path().to_owned()).unwrap();
+    for sub in ["blobs", "keys", "tmp"] {
+        std::fs::create_dir(dir.join(sub)).unwrap();
+    }
+    let cache = ArtefactCache { dir };
+
+    let insert = |url: &str, etag: &str, contents: &[u8]| {
+        let mut temp = cache.temp_file().unwrap();
+        std::io::Write::write_all(&mut temp, contents).unwrap();
+        cache
+            .insert(
+                url,
+                etag,
+                temp.into_temp_path(),
+                Sha256::new_with_prefix(contents),
+            )
+            .unwrap()
+    };
+
+    assert_eq!(cache.lookup("https://a").unwrap(), None);
+    let old = insert("https://a", "\"x\"", b"hello");
+    // Same contents from a different URL share a blob.
+    assert_eq!(insert("https://b", "\"x\"", b"hello"), old);
+    assert_eq!(
+        cache.lookup("https://a").unwrap(),
+        Some(("\"x\"".to_owned(), old.clone()))
+    );
+    assert_eq!(cache.lookup("https://c").unwrap(), None);
+    // A changed artefact replaces the one cached for its URL.
+    let changed = insert("https://b", "\"y\"", b"hello, world");
+    assert_eq!(
+        cache.lookup("https://b").unwrap(),
+        Some(("\"y\"".to_owned(), changed.clone()))
+    );
+
+    File::options()
+        .write(true)
+        .open(&old)
+        .unwrap()
+        .set_modified(SystemTime::now() - Duration::from_secs(30))
+        .unwrap();
+
+    assert_eq!(cache.prune(12).unwrap(), (1, 12));
+    assert_eq!(cache.lookup("https://a").unwrap(), None);
+    assert_eq!(
+        cache.lookup("https://b").unwrap(),
+        Some(("\"y\"".to_owned(), changed))
+    );
+}
+
+#[cfg(test)]
+#[test]
+fn test_artefacts_readable() {
+    use std::os::unix::process::CommandExt;
+    use std::process::Command;
+
+    let tempdir = tempfile::tempdir().unwrap();
+    let dir = Utf8PathBuf::try_from(tempdir.path().to_owned()).unwrap();
+    std::fs::set_permissions(&dir, Permissions::from_mode(0o755)).unwrap();
+    for sub in ["blobs", "keys", "tmp", "input"] {
+        std::fs::create_dir(dir.join(sub)).unwrap();
+    }
+    let cache = ArtefactCache { dir: dir.clone() };
+
+    let mut temp = cache.temp_file().unwrap();
+    std::io::Write::write_all(&mut temp, b"hello").unwrap();
+    let blob = cache
+        .insert(
+            "https://a",
+            "\"x\"",
+            temp.into_temp_path(),
+            Sha256::new_with_prefix(b"hello"),
+        )
+        .unwrap();
+    let artefact = dir.join("input").join("artefact");
+    link_or_copy(&blob, &artefact).unwrap();
+    for path in [&blob, &artefact] {
+        let mode = std::fs::metadata(path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o644, "{}", path);
+    }
+
+    // Read it as another user, as the build user would, if we can.
+    // SAFETY: `geteuid` has no memory safety requirements.
+    if unsafe { libc::geteuid() } == 0 {
+        let output = Command::new("cat")
+            .arg(&artefact)
+            .uid(65534)
+            .gid(65534)
+            .output()
+            .unwrap();
+        assert!(output.status.success(), "{:?}", output);
+        assert_eq!(output.stdout, b"hello");
+    }
+}
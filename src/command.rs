@@ -1,11 +1,118 @@
+use crate::OUR_DATASET;
 use anyhow::{ensure, Result};
+use parse_display::Display;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
 use std::ffi::OsStr;
 use std::process::{Command, ExitStatus, Output};
 
+// The `zfs allow` permissions our steps use. With all of these delegated (on `OUR_DATASET` or
+// `POOL`), `zfs` commands don't need escalating.
+const DELEGATED_PERMISSIONS: &[&str] = &[
+    "clone",
+    "create",
+    "destroy",
+    "mount",
+    "mountpoint",
+    "promote",
+    "quota",
+    "readonly",
+    "snapshot",
+    "userprop",
+];
+
+// How to run commands that need privileges (`zfs` and `chown`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Display, Serialize, Deserialize, clap::ValueEnum)]
+#[display(style = "lowercase")]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Escalate {
+    Pfexec,
+    Sudo,
+    Doas,
+    // Already root, or has delegated ZFS permissions
+    None,
+}
+
+impl Escalate {
+    // Delegated permissions are only enough for isolated jobs, whose datasets are all under
+    // `OUR_DATASET`. Other jobs use `rpool/work` and `rpool/input`, and chown their mountpoints.
+    pub(crate) fn detect(isolated: bool) -> Escalate {
+        // SAFETY: `geteuid` is always successful.
+        if unsafe { libc::geteuid() } == 0 || (isolated && has_delegated_permissions()) {
+            return Escalate::None;
+        }
+        let path = std::env::var_os("PATH").unwrap_or_default();
+        [Escalate::Pfexec, Escalate::Sudo, Escalate::Doas]
+            .into_iter()
+            .find(|escalate| {
+                std::env::split_paths(&path).any(|dir| dir.join(escalate.to_string()).is_file())
+            })
+            .unwrap_or(Escalate::None)
+    }
+
+    pub(crate) fn command(self, program: impl AsRef<OsStr>) -> Command {
+        if self == Escalate::None {
+            Command::new(program)
+        } else {
+            let mut command = Command::new(self.to_string());
+            command.arg(program);
+            command
+        }
+    }
+}
+
+// Whether we've been delegated everything in `DELEGATED_PERMISSIONS`. If `OUR_DATASET` doesn't
+// exist yet, `zfs allow` fails and we assume not.
+fn has_delegated_permissions() -> bool {
+    let (Ok(allow), Ok(id)) = (
+        Command::new("zfs").args(["allow", OUR_DATASET]).output(),
+        Command::new("id").arg("-un").output(),
+    ) else {
+        return false;
+    };
+    if !allow.status.success() || !id.status.success() {
+        return false;
+    }
+    let (allow, user) = (
+        String::from_utf8_lossy(&allow.stdout),
+        String::from_utf8_lossy(&id.stdout),
+    );
+    let permissions = delegated_permissions(&allow, user.trim());
+    DELEGATED_PERMISSIONS
+        .iter()
+        .all(|permission| permissions.contains(permission))
+}
+
+// The permissions `user` (or everyone) has on descendents of a dataset, from its `zfs allow`
+// output. Permissions that only apply to the dataset itself don't help us create children.
+fn delegated_permissions<'a>(allow: &'a str, user: &str) -> BTreeSet<&'a str> {
+    let mut permissions = BTreeSet::new();
+    let mut applies = false;
+    for line in allow.lines() {
+        if !line.starts_with(char::is_whitespace) {
+            applies = line.starts_with("Local+Descendent permissions")
+                || line.starts_with("Descendent permissions");
+            continue;
+        }
+        if !applies {
+            continue;
+        }
+        let mut fields = line.split_whitespace();
+        let granted = match (fields.next(), fields.next()) {
+            (Some("user"), Some(name)) if name == user => fields.next(),
+            (Some("everyone"), granted) => granted,
+            _ => None,
+        };
+        permissions.extend(granted.into_iter().flat_map(|granted| granted.split(',')));
+    }
+    permissions
+}
+
 pub(crate) trait CommandExt {
     fn succeed(&mut self) -> Result<()>;
     fn succeed_output(&mut self) -> Result<Output>;
     fn to_string(&self) -> String;
+    fn argv(&self) -> Vec<String>;
 }
 
 impl CommandExt for Command {
@@ -21,15 +128,18 @@ impl CommandExt for Command {
     }
 
     fn to_string(&self) -> String {
-        shell_words::join(
-            std::iter::once(self.get_program())
-                .chain(self.get_args())
-                .map(OsStr::to_string_lossy),
-        )
+        shell_words::join(self.argv())
+    }
+
+    fn argv(&self) -> Vec<String> {
+        std::iter::once(self.get_program())
+            .chain(self.get_args())
+            .map(|arg| arg.to_string_lossy().into_owned())
+            .collect()
     }
 }
 
-fn check(command: &Command, status: ExitStatus) -> Result<()> {
+pub(crate) fn check(command: &Command, status: ExitStatus) -> Result<()> {
     ensure!(
         status.success(),
         "`{}` failed with {}",
@@ -38,3 +148,29 @@ fn check(command: &Command, status: ExitStatus) -> Result<()> {
     );
     Ok(())
 }
+
+#[cfg(test)]
+#[test]
+fn test_delegated_permissions() {
+    let allow = "\
+---- Permissions on rpool/buildomat-at-home ---------------------------
+Local permissions:
+\tuser alice quota
+Local+Descendent permissions:
+\tuser alice create,destroy,mount
+\tuser bob snapshot
+---- Permissions on rpool --------------------------------------------
+Descendent permissions:
+\teveryone readonly
+Create time permissions:
+\tdestroy
+";
+    assert_eq!(
+        delegated_permissions(allow, "alice"),
+        BTreeSet::from(["create", "destroy", "mount", "readonly"])
+    );
+    assert_eq!(
+        delegated_permissions(allow, "carol"),
+        BTreeSet::from(["readonly"])
+    );
+}
@@ -0,0 +1,143 @@
+use crate::config_dir;
+use anyhow::{Context, Result};
+use camino::Utf8PathBuf;
+use serde::{Deserialize, Deserializer};
+use std::collections::{BTreeMap, HashMap};
+use std::io::ErrorKind;
+use std::time::Duration;
+
+// Read from `~/.config/buildomat-at-home/config.toml` (or under `$XDG_CONFIG_HOME`).
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct Config {
+    #[serde(skip)]
+    pub(crate) path: Utf8PathBuf,
+    // Default user for `--build-user`
+    pub(crate) build_user: Option<String>,
+    // Default for `--remote`
+    pub(crate) remote: Option<String>,
+    #[serde(default)]
+    pub(crate) repos: HashMap<String, RepoConfig>,
+    #[serde(default)]
+    pub(crate) targets: HashMap<String, TargetConfig>,
+    #[serde(default)]
+    pub(crate) home: HomeConfig,
+}
+
+// Settings for jobs in a particular repository, keyed by `owner/name`.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct RepoConfig {
+    // Variables to set for the job script (before any `--env`)
+    #[serde(default)]
+    pub(crate) env: BTreeMap<String, String>,
+    // Variables to pass through from our environment (in addition to any `--pass-env`)
+    #[serde(default)]
+    pub(crate) pass_env: Vec<String>,
+}
+
+// Settings for jobs with a particular `target` in their frontmatter.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct TargetConfig {
+    // Image tarball or directory to use as the root filesystem with `--image`
+    pub(crate) image: Option<Utf8PathBuf>,
+    // Default for `--timeout`
+    #[serde(default, deserialize_with = "deserialize_duration")]
+    pub(crate) timeout: Option<Duration>,
+    // Resource limits matching the target's instance size
+    #[serde(default, deserialize_with = "deserialize_size")]
+    pub(crate) memory: Option<u64>,
+    pub(crate) cpus: Option<usize>,
+    pub(crate) open_files: Option<u64>,
+    // Quota for `/work`
+    #[serde(default, deserialize_with = "deserialize_size")]
+    pub(crate) disk: Option<u64>,
+}
+
+// Settings for `--fresh-home`.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct HomeConfig {
+    // Paths in the real home directory to symlink into the fresh one, if they exist
+    #[serde(default = "default_home_links")]
+    pub(crate) link: Vec<Utf8PathBuf>,
+    // Variable holding a GitHub token to write to `.netrc`
+    pub(crate) netrc_token_env: Option<String>,
+}
+
+impl Default for HomeConfig {
+    fn default() -> HomeConfig {
+        HomeConfig {
+            link: default_home_links(),
+            netrc_token_env: None,
+        }
+    }
+}
+
+// Enough to run the rustup-managed toolchains already installed.
+fn default_home_links() -> Vec<Utf8PathBuf> {
+    vec![".rustup".into(), ".cargo/bin".into()]
+}
+
+impl Config {
+    pub(crate) fn load() -> Result<Config> {
+        let path = config_dir()?.join("config.toml");
+        let mut config: Config = match std::fs::read_to_string(&path) {
+            Ok(s) => toml::from_str(&s).with_context(|| format!("failed to parse {}", path))?,
+            Err(err) if err.kind() == ErrorKind::NotFound => Config::default(),
+            Err(err) => return Err(err).with_context(|| format!("failed to read {}", path)),
+        };
+        config.path = path;
+        Ok(config)
+    }
+}
+
+fn deserialize_duration<'de, D>(deserializer: D) -> Result<Option<Duration>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let s = String::deserialize(deserializer)?;
+    humantime::parse_duration(&s)
+        .map(Some)
+        .map_err(serde::de::Error::custom)
+}
+
+fn deserialize_size<'de, D>(deserializer: D) -> Result<Option<u64>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let s = String::deserialize(deserializer)?;
+    parse_size(&s).map(Some).map_err(serde::de::Error::custom)
+}
+
+// Parses a size in bytes with an optional binary unit suffix, like `16GiB` or `512M`.
+fn parse_size(s: &str) -> Result<u64, String> {
+    let s = s.trim();
+    let split = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (number, unit) = s.split_at(split);
+    let shift = match unit.trim_start() {
+        "" | "B" => 0,
+        "K" | "KiB" => 10,
+        "M" | "MiB" => 20,
+        "G" | "GiB" => 30,
+        "T" | "TiB" => 40,
+        _ => return Err(format!("unknown unit in size `{}`", s)),
+    };
+    let number: u64 = number
+        .parse()
+        .map_err(|_| format!("invalid size `{}`", s))?;
+    number
+        .checked_mul(1 << shift)
+        .ok_or_else(|| format!("size `{}` is too large", s))
+}
+
+#[cfg(test)]
+#[test]
+fn test_parse_size() {
+    assert_eq!(parse_size("4096"), Ok(4096));
+    assert_eq!(parse_size("512M"), Ok(512 << 20));
+    assert_eq!(parse_size("16 GiB"), Ok(16 << 30));
+    assert!(parse_size("16GB").is_err());
+    assert!(parse_size("GiB").is_err());
+}
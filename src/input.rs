@@ -1,7 +1,8 @@
 use parse_display::{Display, FromStr};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use ulid::Ulid;
 
-#[derive(Debug, Display, FromStr, PartialEq, Eq, PartialOrd, Ord)]
+#[derive(Debug, Clone, Display, FromStr, PartialEq, Eq, PartialOrd, Ord)]
 pub(crate) enum Input {
     #[display("local/{id}")]
     LocalBuild { id: Ulid },
@@ -16,6 +17,20 @@ pub(crate) enum Input {
     },
 }
 
+impl Serialize for Input {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for Input {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Input, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(serde::de::Error::custom)
+    }
+}
+
 #[cfg(test)]
 #[test]
 fn test_from_str() {
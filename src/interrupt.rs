@@ -0,0 +1,84 @@
+use anyhow::{bail, Result};
+use std::sync::atomic::{AtomicBool, AtomicI32, Ordering};
+use std::time::Duration;
+
+// The first SIGINT or SIGTERM we received, or 0.
+static SIGNAL: AtomicI32 = AtomicI32::new(0);
+// The running job script's process group, or 0.
+static JOB_PGID: AtomicI32 = AtomicI32::new(0);
+// Set while an interactive shell is in the foreground, where Ctrl-C is meant for the shell.
+static IGNORE: AtomicBool = AtomicBool::new(false);
+
+// Records SIGINT and SIGTERM rather than exiting, so that running steps can stop cleanly. A second
+// signal kills the job script's process group and exits immediately.
+pub(crate) fn install() -> Result<()> {
+    for signal in [libc::SIGINT, libc::SIGTERM] {
+        // SAFETY: `handle` only calls async-signal-safe functions.
+        unsafe {
+            let mut action: libc::sigaction = std::mem::zeroed();
+            action.sa_sigaction = handle as extern "C" fn(libc::c_int) as libc::sighandler_t;
+            action.sa_flags = libc::SA_RESTART;
+            if libc::sigaction(signal, std::ptr::addr_of!(action), std::ptr::null_mut()) == -1 {
+                return Err(std::io::Error::last_os_error().into());
+            }
+        }
+    }
+    Ok(())
+}
+
+extern "C" fn handle(signal: libc::c_int) {
+    if IGNORE.load(Ordering::SeqCst) {
+        return;
+    }
+    if SIGNAL.swap(signal, Ordering::SeqCst) != 0 {
+        let pgid = JOB_PGID.load(Ordering::SeqCst);
+        // SAFETY: `kill` and `_exit` are async-signal-safe.
+        unsafe {
+            if pgid != 0 {
+                libc::kill(-pgid, libc::SIGKILL);
+            }
+            libc::_exit(128 + signal);
+        }
+    }
+}
+
+pub(crate) fn signal() -> Option<libc::c_int> {
+    match SIGNAL.load(Ordering::SeqCst) {
+        0 => None,
+        signal => Some(signal),
+    }
+}
+
+pub(crate) fn check() -> Result<()> {
+    match signal() {
+        Some(signal) => bail!("interrupted by {}", name(signal)),
+        None => Ok(()),
+    }
+}
+
+// Resolves once a signal has been received.
+pub(crate) async fn wait() {
+    while signal().is_none() {
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+}
+
+pub(crate) fn set_job_pgid(pgid: libc::pid_t) {
+    JOB_PGID.store(pgid, Ordering::SeqCst);
+}
+
+// Runs `f` (which waits on an interactive child process) without recording signals.
+pub(crate) fn ignoring<T>(f: impl FnOnce() -> T) -> T {
+    IGNORE.store(true, Ordering::SeqCst);
+    let result = f();
+    IGNORE.store(false, Ordering::SeqCst);
+    result
+}
+
+pub(crate) fn name(signal: libc::c_int) -> &'static str {
+    match signal {
+        libc::SIGINT => "SIGINT",
+        libc::SIGTERM => "SIGTERM",
+        _ => "a signal",
+    }
+}
@@ -0,0 +1,315 @@
+use camino::{Utf8Path, Utf8PathBuf};
+use serde::{Deserialize, Serialize};
+use std::process::Command;
+
+// Where the job script is bind-mounted when running in an image's root filesystem, which can't
+// see the host's copy.
+const IMAGE_SCRIPT_PATH: &str = "/tmp/buildomat-at-home-job.sh";
+
+// Describes how to run a job script inside its own user and mount namespaces: `work` (a path on
+// the host) is bind-mounted at `/work`, a tmpfs is mounted at `/input`, and each input's host path
+// is bind-mounted at its path under `/input`. Nothing outside the namespaces sees these mounts, so
+// several isolated jobs can run at once without touching the host's `/work` and `/input`.
+//
+// If `root` is set, the job is chrooted into it after mounting `/work` and `/input` (and the host's
+// `/dev`, `/proc` and `/sys`, a fresh `/tmp`, and `home` at the same path) within it.
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct Isolation {
+    // The job's work dataset, and where it's mounted on the host
+    pub(crate) dataset: String,
+    pub(crate) work: Utf8PathBuf,
+    // (source on the host, mountpoint within the namespace)
+    pub(crate) inputs: Vec<(Utf8PathBuf, Utf8PathBuf)>,
+    pub(crate) root: Option<Utf8PathBuf>,
+    pub(crate) home: Option<Utf8PathBuf>,
+}
+
+impl Isolation {
+    pub(crate) fn describe(&self) -> Vec<String> {
+        self.root
+            .iter()
+            .map(|root| format!("/ = {}", root))
+            .chain(
+                self.home
+                    .iter()
+                    .filter(|_| self.root.is_some())
+                    .map(|home| format!("{} = {}", home, home)),
+            )
+            .chain(std::iter::once(format!("/work = {}", self.work)))
+            .chain(
+                self.inputs
+                    .iter()
+                    .map(|(source, mountpoint)| format!("{} = {}", mountpoint, source)),
+            )
+            .collect()
+    }
+
+    // The path the job script will be at within the namespace.
+    pub(crate) fn script_path<'a>(&self, script: &'a Utf8Path) -> &'a Utf8Path {
+        if self.root.is_some() {
+            Utf8Path::new(IMAGE_SCRIPT_PATH)
+        } else {
+            script
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    pub(crate) fn apply(
+        &self,
+        command: &mut Command,
+        script: &Utf8Path,
+        workdir: &Utf8Path,
+    ) -> anyhow::Result<()> {
+        use std::os::unix::process::CommandExt;
+
+        let setup = linux::Setup::new(self, script, workdir)?;
+        // SAFETY: `Setup::run` only makes system calls using memory allocated before the fork.
+        unsafe {
+            command.pre_exec(move || setup.run());
+        }
+        Ok(())
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub(crate) fn apply(
+        &self,
+        _command: &mut Command,
+        _script: &Utf8Path,
+        _workdir: &Utf8Path,
+    ) -> anyhow::Result<()> {
+        anyhow::bail!("isolated jobs are only supported on Linux");
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod linux {
+    use super::{Isolation, IMAGE_SCRIPT_PATH};
+    use anyhow::Result;
+    use camino::Utf8Path;
+    use std::ffi::{CStr, CString};
+    use std::io;
+    use std::ptr;
+
+    enum Op {
+        Mount {
+            source: Option<CString>,
+            target: CString,
+            fstype: Option<CString>,
+            flags: libc::c_ulong,
+            // Ignore failures (for mounts that are nice to have)
+            optional: bool,
+        },
+        // Create a directory if it doesn't exist
+        Mkdir(CString),
+        // Create an empty file to bind-mount a file onto
+        Touch(CString),
+        Chroot(CString),
+    }
+
+    // Everything the child needs is allocated here, before the fork; after forking a
+    // multithreaded process, the child must not allocate.
+    pub(super) struct Setup {
+        uid_map: String,
+        gid_map: String,
+        ops: Vec<Op>,
+        workdir: CString,
+    }
+
+    impl Setup {
+        pub(super) fn new(
+            isolation: &Isolation,
+            script: &Utf8Path,
+            workdir: &Utf8Path,
+        ) -> Result<Setup> {
+            // SAFETY: `getuid` and `getgid` are always successful.
+            let (uid, gid) = unsafe { (libc::getuid(), libc::getgid()) };
+
+            let root = isolation.root.as_deref().unwrap_or(Utf8Path::new("/"));
+            let path = |p: &str| CString::new(root.join(p.trim_start_matches('/')).as_str());
+            let bind = |source: &str, target: &str, optional: bool| -> Result<Op> {
+                Ok(Op::Mount {
+                    source: Some(CString::new(source)?),
+                    target: path(target)?,
+                    fstype: None,
+                    flags: libc::MS_BIND | libc::MS_REC,
+                    optional,
+                })
+            };
+            let tmpfs = |target: &str| -> Result<Op> {
+                Ok(Op::Mount {
+                    source: Some(CString::new("tmpfs")?),
+                    target: path(target)?,
+                    fstype: Some(CString::new("tmpfs")?),
+                    flags: 0,
+                    optional: false,
+                })
+            };
+
+            // Keep our mounts from propagating back to the host.
+            let mut ops = vec![Op::Mount {
+                source: None,
+                target: CString::new("/")?,
+                fstype: None,
+                flags: libc::MS_REC | libc::MS_PRIVATE,
+                optional: false,
+            }];
+
+            if isolation.root.is_some() {
+                for dir in ["/dev", "/proc", "/sys"] {
+                    ops.push(Op::Mkdir(path(dir)?));
+                    ops.push(bind(dir, dir, false)?);
+                }
+                ops.push(Op::Mkdir(path("/tmp")?));
+                ops.push(tmpfs("/tmp")?);
+                ops.push(Op::Touch(path(IMAGE_SCRIPT_PATH)?));
+                ops.push(bind(script.as_str(), IMAGE_SCRIPT_PATH, false)?);
+                ops.push(bind("/etc/resolv.conf", "/etc/resolv.conf", true)?);
+                ops.push(Op::Mkdir(path("/work")?));
+                ops.push(Op::Mkdir(path("/input")?));
+                if let Some(home) = &isolation.home {
+                    let ancestors = home.ancestors().collect::<Vec<_>>();
+                    for dir in ancestors.into_iter().rev().skip(1) {
+                        ops.push(Op::Mkdir(path(dir.as_str())?));
+                    }
+                    ops.push(bind(home.as_str(), home.as_str(), false)?);
+                }
+            }
+
+            ops.push(bind(isolation.work.as_str(), "/work", false)?);
+            ops.push(tmpfs("/input")?);
+            for (source, mountpoint) in &isolation.inputs {
+                let parents = mountpoint
+                    .ancestors()
+                    .filter(|p| p.starts_with("/input") && *p != "/input")
+                    .collect::<Vec<_>>();
+                for dir in parents.into_iter().rev() {
+                    ops.push(Op::Mkdir(path(dir.as_str())?));
+                }
+                ops.push(bind(source.as_str(), mountpoint.as_str(), false)?);
+            }
+
+            if let Some(root) = &isolation.root {
+                ops.push(Op::Chroot(CString::new(root.as_str())?));
+            }
+
+            Ok(Setup {
+                uid_map: format!("{} {} 1", uid, uid),
+                gid_map: format!("{} {} 1", gid, gid),
+                ops,
+                workdir: CString::new(workdir.as_str())?,
+            })
+        }
+
+        pub(super) fn run(&self) -> io::Result<()> {
+            // SAFETY: all pointers passed here are to valid NUL-terminated strings.
+            unsafe {
+                cvt(libc::unshare(libc::CLONE_NEWUSER | libc::CLONE_NEWNS))?;
+                write_file(c"/proc/self/setgroups", b"deny")?;
+                write_file(c"/proc/self/uid_map", self.uid_map.as_bytes())?;
+                write_file(c"/proc/self/gid_map", self.gid_map.as_bytes())?;
+
+                for op in &self.ops {
+                    match op {
+                        Op::Mount {
+                            source,
+                            target,
+                            fstype,
+                            flags,
+                            optional,
+                        } => {
+                            let result = cvt(libc::mount(
+                                source.as_deref().map_or(ptr::null(), CStr::as_ptr),
+                                target.as_ptr(),
+                                fstype.as_deref().map_or(ptr::null(), CStr::as_ptr),
+                                *flags,
+                                ptr::null(),
+                            ));
+                            if !optional {
+                                result?;
+                            }
+                        }
+                        Op::Mkdir(path) => {
+                            if libc::mkdir(path.as_ptr(), 0o755) == -1 {
+                                let err = io::Error::last_os_error();
+                                if err.kind() != io::ErrorKind::AlreadyExists {
+                                    return Err(err);
+                                }
+                            }
+                        }
+                        Op::Touch(path) => {
+                            let fd = libc::open(
+                                path.as_ptr(),
+                                libc::O_WRONLY | libc::O_CREAT | libc::O_CLOEXEC,
+                                0o644,
+                            );
+                            cvt(fd)?;
+                            libc::close(fd);
+                        }
+                        Op::Chroot(path) => cvt(libc::chroot(path.as_ptr()))?,
+                    }
+                }
+
+                // The working directory was changed before this runs, so it refers to whatever
+                // was underneath the new mounts.
+                cvt(libc::chdir(self.workdir.as_ptr()))?;
+            }
+            Ok(())
+        }
+    }
+
+    unsafe fn write_file(path: &CStr, contents: &[u8]) -> io::Result<()> {
+        let fd = libc::open(path.as_ptr(), libc::O_WRONLY | libc::O_CLOEXEC);
+        cvt(fd)?;
+        let result = if libc::write(fd, contents.as_ptr().cast(), contents.len()) < 0 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(())
+        };
+        libc::close(fd);
+        result
+    }
+
+    fn cvt(ret: libc::c_int) -> io::Result<()> {
+        if ret == -1 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn test_describe_image() {
+    let isolation = Isolation {
+        dataset: "rpool/buildomat-at-home/work/01H3XMET848BWFBC9KFRN1KCWX".into(),
+        work: "/var/tmp/buildomat-at-home/work/01H3XMET848BWFBC9KFRN1KCWX".into(),
+        inputs: vec![(
+            "/var/tmp/buildomat-at-home/input/local/01H3WX25SMVQ9YEDXDDC832VCV".into(),
+            "/input/build/work".into(),
+        )],
+        root: Some("/home/me/.cache/buildomat-at-home/images/0123abcd".into()),
+        home: Some("/var/tmp/buildomat-at-home/home/01H3XMET848BWFBC9KFRN1KCWX".into()),
+    };
+    assert_eq!(
+        isolation.describe(),
+        [
+            "/ = /home/me/.cache/buildomat-at-home/images/0123abcd",
+            "/var/tmp/buildomat-at-home/home/01H3XMET848BWFBC9KFRN1KCWX = \
+            /var/tmp/buildomat-at-home/home/01H3XMET848BWFBC9KFRN1KCWX",
+            "/work = /var/tmp/buildomat-at-home/work/01H3XMET848BWFBC9KFRN1KCWX",
+            "/input/build/work = \
+            /var/tmp/buildomat-at-home/input/local/01H3WX25SMVQ9YEDXDDC832VCV",
+        ]
+    );
+    // The host's copy of the script isn't visible from within the image.
+    let script = Utf8Path::new("/src/omicron/.github/buildomat/jobs/build.sh");
+    assert_eq!(isolation.script_path(script), IMAGE_SCRIPT_PATH);
+    let isolation = Isolation {
+        root: None,
+        home: None,
+        ..isolation
+    };
+    assert_eq!(isolation.script_path(script), script);
+    assert_eq!(isolation.describe().len(), 2);
+}
@@ -0,0 +1,268 @@
+use crate::command::{self, CommandExt as _, Escalate};
+use crate::interrupt;
+use crate::limits::Cgroup;
+use crate::log::JobLog;
+use anyhow::{bail, Result};
+use dialoguer::console::style;
+use humantime::format_duration;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::os::unix::process::CommandExt;
+use std::process::{Child, Command, Stdio};
+use std::sync::{mpsc, Arc};
+use std::time::{Duration, Instant};
+
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+// How long to wait after SIGTERM before sending SIGKILL.
+const KILL_GRACE: Duration = Duration::from_secs(10);
+// How long to wait for the script's output after it exits. Processes it started in the
+// background may hold its stdout and stderr open for much longer.
+const OUTPUT_GRACE: Duration = Duration::from_secs(1);
+
+// Runs the job script in its own process group, so that everything it started can be signalled
+// together, copying its output to `log` as well as our stdout and stderr. The group doesn't get
+// the terminal's signals, so SIGINT and SIGTERM sent to us are forwarded to it. If the script runs
+// as another user, `escalate` is how to get permission to signal it.
+pub(crate) async fn run(
+    command: &mut Command,
+    timeout: Option<Duration>,
+    log: &Arc<JobLog>,
+    cgroup: Option<&Cgroup>,
+    reap: bool,
+    escalate: Option<Escalate>,
+) -> Result<()> {
+    command.process_group(0);
+    command.stdout(Stdio::piped()).stderr(Stdio::piped());
+    let mut child = command.spawn()?;
+    let pgid = libc::pid_t::try_from(child.id())?;
+    interrupt::set_job_pgid(pgid);
+    let result = wait(command, &mut child, pgid, timeout, log, escalate).await;
+    interrupt::set_job_pgid(0);
+    // Whatever happened to the script, anything it started in the background is still running.
+    // Failing to clean up doesn't change how the script exited.
+    if let Err(err) = clean_up(pgid, cgroup, reap, escalate).await {
+        eprintln!(
+            "{} failed to clean up after the job script: {:#}",
+            style("==>").yellow(),
+            err
+        );
+    }
+    result
+}
+
+async fn wait(
+    command: &Command,
+    child: &mut Child,
+    pgid: libc::pid_t,
+    timeout: Option<Duration>,
+    log: &Arc<JobLog>,
+    escalate: Option<Escalate>,
+) -> Result<()> {
+    let deadline = timeout.map(|timeout| Instant::now() + timeout);
+
+    let (tx, rx) = mpsc::channel();
+    if let Some(stdout) = child.stdout.take() {
+        tee(stdout, std::io::stdout(), "stdout", log, &tx);
+    }
+    if let Some(stderr) = child.stderr.take() {
+        tee(stderr, std::io::stderr(), "stderr", log, &tx);
+    }
+    drop(tx);
+
+    let result = loop {
+        if let Some(status) = child.try_wait()? {
+            wait_for_output(&rx).await?;
+            log.status(&format!("exited with {}", status))?;
+            break command::check(command, status);
+        }
+        if let Some(signal) = interrupt::signal() {
+            eprintln!(
+                "{} interrupted; sending {} to the job",
+                style("==>").red(),
+                interrupt::name(signal)
+            );
+            terminate(pgid, child, signal, escalate).await?;
+            wait_for_output(&rx).await?;
+            log.status(&format!("interrupted by {}", interrupt::name(signal)))?;
+            bail!(
+                "`{}` interrupted by {}",
+                command.to_string(),
+                interrupt::name(signal)
+            );
+        }
+        if let (Some(timeout), Some(deadline)) = (timeout, deadline) {
+            if Instant::now() >= deadline {
+                eprintln!(
+                    "{} job timed out after {}; terminating",
+                    style("==>").red(),
+                    format_duration(timeout)
+                );
+                terminate(pgid, child, libc::SIGTERM, escalate).await?;
+                wait_for_output(&rx).await?;
+                log.status(&format!("timed out after {}", format_duration(timeout)))?;
+                bail!(
+                    "`{}` timed out after {}",
+                    command.to_string(),
+                    format_duration(timeout)
+                );
+            }
+        }
+        tokio::time::sleep(POLL_INTERVAL).await;
+    };
+    result
+}
+
+// Reports processes the script left running in its process group (or its cgroup, which also
+// catches daemons that started a new session), and terminates them unless `reap` is false.
+async fn clean_up(
+    pgid: libc::pid_t,
+    cgroup: Option<&Cgroup>,
+    reap: bool,
+    escalate: Option<Escalate>,
+) -> Result<()> {
+    let output = Command::new("pgrep")
+        .args(["-g", &pgid.to_string()])
+        .output()?;
+    let mut pids = std::str::from_utf8(&output.stdout)?
+        .lines()
+        .map(str::parse)
+        .collect::<Result<Vec<libc::pid_t>, _>>()?;
+    if let Some(cgroup) = cgroup {
+        pids.extend(cgroup.pids()?);
+    }
+    pids.sort_unstable();
+    pids.dedup();
+    if pids.is_empty() {
+        return Ok(());
+    }
+
+    eprintln!(
+        "{} the job script left {} processes running{}:",
+        style("==>").yellow(),
+        pids.len(),
+        if reap { "; terminating them" } else { "" }
+    );
+    let list = pids
+        .iter()
+        .map(ToString::to_string)
+        .collect::<Vec<_>>()
+        .join(",");
+    let output = Command::new("ps")
+        .args(["-o", "pid=,args=", "-p", &list])
+        .output()?;
+    for line in String::from_utf8_lossy(&output.stdout).lines() {
+        eprintln!("    {}", line.trim());
+    }
+    if !reap {
+        return Ok(());
+    }
+
+    let deadline = Instant::now() + KILL_GRACE;
+    send(&pids, libc::SIGTERM, escalate);
+    while Instant::now() < deadline {
+        pids.retain(|&pid| exists(pid));
+        if pids.is_empty() {
+            return Ok(());
+        }
+        tokio::time::sleep(POLL_INTERVAL).await;
+    }
+    send(&pids, libc::SIGKILL, escalate);
+    Ok(())
+}
+
+// Copies lines from `reader` to `out` and `log` on a new thread, sending the result to `tx` when
+// `reader` is closed.
+fn tee(
+    reader: impl Read + Send + 'static,
+    mut out: impl Write + Send + 'static,
+    stream: &'static str,
+    log: &Arc<JobLog>,
+    tx: &mpsc::Sender<std::io::Result<()>>,
+) {
+    let log = Arc::clone(log);
+    let tx = tx.clone();
+    std::thread::spawn(move || {
+        let mut reader = BufReader::new(reader);
+        let mut line = Vec::new();
+        let result = loop {
+            line.clear();
+            match reader.read_until(b'\n', &mut line) {
+                Ok(0) => break Ok(()),
+                Ok(_) => {}
+                Err(err) => break Err(err),
+            }
+            if let Err(err) = out
+                .write_all(&line)
+                .and_then(|()| out.flush())
+                .and_then(|()| log.record(stream, &line))
+            {
+                break Err(err);
+            }
+        };
+        tx.send(result).ok();
+    });
+}
+
+async fn wait_for_output(rx: &mpsc::Receiver<std::io::Result<()>>) -> Result<()> {
+    let deadline = Instant::now() + OUTPUT_GRACE;
+    while Instant::now() < deadline {
+        match rx.try_recv() {
+            Ok(result) => result?,
+            Err(mpsc::TryRecvError::Empty) => tokio::time::sleep(POLL_INTERVAL).await,
+            Err(mpsc::TryRecvError::Disconnected) => break,
+        }
+    }
+    Ok(())
+}
+
+// Sends `signal` to the process group, then SIGKILL to whatever is left of it after `KILL_GRACE`.
+async fn terminate(
+    pgid: libc::pid_t,
+    child: &mut Child,
+    signal: libc::c_int,
+    escalate: Option<Escalate>,
+) -> Result<()> {
+    send(&[-pgid], signal, escalate);
+    let deadline = Instant::now() + KILL_GRACE;
+    while Instant::now() < deadline {
+        if child.try_wait()?.is_some() && !exists(-pgid) {
+            return Ok(());
+        }
+        tokio::time::sleep(POLL_INTERVAL).await;
+    }
+    send(&[-pgid], libc::SIGKILL, escalate);
+    child.wait()?;
+    Ok(())
+}
+
+// Sends `signal` to each process (or, if negative, process group) in `targets`. Failures (likely
+// because they've already exited) are ignored.
+fn send(targets: &[libc::pid_t], signal: libc::c_int, escalate: Option<Escalate>) {
+    if let Some(escalate) = escalate {
+        let name = match signal {
+            libc::SIGINT => "INT",
+            libc::SIGKILL => "KILL",
+            _ => "TERM",
+        };
+        escalate
+            .command("kill")
+            .args(["-s", name, "--"])
+            .args(targets.iter().map(ToString::to_string))
+            .stderr(Stdio::null())
+            .status()
+            .ok();
+    } else {
+        for &target in targets {
+            // SAFETY: `kill` has no memory safety requirements.
+            unsafe {
+                libc::kill(target, signal);
+            }
+        }
+    }
+}
+
+// Whether a process (or, if negative, process group) exists, even if we can't signal it.
+fn exists(target: libc::pid_t) -> bool {
+    // SAFETY: `kill` has no memory safety requirements.
+    let result = unsafe { libc::kill(target, 0) };
+    result == 0 || std::io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
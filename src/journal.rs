@@ -0,0 +1,479 @@
+use crate::command::CommandExt;
+use crate::plan::Plan;
+use crate::saved::Precondition;
+use crate::state_dir;
+use crate::step::Step;
+use anyhow::{ensure, Context, Result};
+use camino::{Utf8Path, Utf8PathBuf};
+use dialoguer::console::style;
+use serde::Deserialize;
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::ErrorKind;
+use std::time::{Duration, SystemTime};
+use tempfile::NamedTempFile;
+use ulid::Ulid;
+
+// Datasets that a run created but didn't finish setting up (an input whose artefacts weren't all
+// downloaded, or an isolated work dataset the job script hadn't started in), one per line. The
+// journal is written before each such dataset is created, so it's accurate however the run ends;
+// the next plan destroys whatever it lists, other than datasets of plans that could still be
+// resumed.
+#[derive(Debug)]
+pub(crate) struct Journal {
+    path: Utf8PathBuf,
+    incomplete: BTreeSet<String>,
+}
+
+impl Journal {
+    pub(crate) fn load() -> Result<Journal> {
+        Journal::load_from(state_dir()?.join("journal"))
+    }
+
+    fn load_from(path: Utf8PathBuf) -> Result<Journal> {
+        let incomplete = match std::fs::read_to_string(&path) {
+            Ok(contents) => contents.lines().map(ToOwned::to_owned).collect(),
+            Err(err) if err.kind() == ErrorKind::NotFound => BTreeSet::new(),
+            Err(err) => {
+                return Err(err).with_context(|| format!("failed to read {}", path));
+            }
+        };
+        Ok(Journal { path, incomplete })
+    }
+
+    pub(crate) fn incomplete(&self) -> &BTreeSet<String> {
+        &self.incomplete
+    }
+
+    // Records what `step` leaves incomplete if the run ends while it's running.
+    pub(crate) fn starting(&mut self, step: &Step) -> Result<()> {
+        if let Some(dataset) = step.starts_incomplete() {
+            self.add(dataset)?;
+        }
+        // A failed job's work dataset is left for inspection.
+        if let Some(dataset) = step.keeps() {
+            self.remove(dataset)?;
+        }
+        Ok(())
+    }
+
+    pub(crate) fn finished(&mut self, step: &Step) -> Result<()> {
+        if let Some(dataset) = step.completes() {
+            self.remove(dataset)?;
+        }
+        Ok(())
+    }
+
+    fn add(&mut self, dataset: &str) -> Result<()> {
+        if self.incomplete.insert(dataset.to_owned()) {
+            self.save()?;
+        }
+        Ok(())
+    }
+
+    fn remove(&mut self, dataset: &str) -> Result<()> {
+        if self.incomplete.remove(dataset) {
+            self.save()?;
+        }
+        Ok(())
+    }
+
+    fn save(&self) -> Result<()> {
+        let mut contents = String::new();
+        for dataset in &self.incomplete {
+            contents.push_str(dataset);
+            contents.push('\n');
+        }
+        write_atomically(&self.path, contents.as_bytes())
+    }
+}
+
+fn write_atomically(path: &Utf8Path, contents: &[u8]) -> Result<()> {
+    let parent = path
+        .parent()
+        .expect("journal path must have parent directory");
+    std::fs::create_dir_all(parent)?;
+    let mut temp = NamedTempFile::new_in(parent)?;
+    std::io::Write::write_all(&mut temp, contents)?;
+    temp.persist(path)?;
+    Ok(())
+}
+
+fn plans_dir() -> Result<Utf8PathBuf> {
+    Ok(state_dir()?.join("plans"))
+}
+
+// Plans older than this are forgotten, finished or not.
+const PLAN_MAX_AGE: Duration = Duration::from_hours(30 * 24);
+
+// A plan's progress, rewritten to `plans/ID.json` after each step: the plan itself, how many of
+// its steps have completed, the error that stopped it (if any), and the preconditions it would
+// have if it were saved by `plan` now. A plan that fails or is interrupted can be resumed from
+// here (`--resume`) without being rebuilt, as long as the system hasn't changed since.
+#[derive(Debug)]
+pub(crate) struct StepJournal {
+    path: Utf8PathBuf,
+    completed: usize,
+}
+
+impl StepJournal {
+    pub(crate) fn create(plan: &Plan) -> Result<StepJournal> {
+        StepJournal::create_in(&plans_dir()?, plan, SystemTime::now())
+    }
+
+    fn create_in(dir: &Utf8Path, plan: &Plan, now: SystemTime) -> Result<StepJournal> {
+        prune(dir, now)?;
+        let journal = StepJournal {
+            path: dir.join(format!("{}.json", plan.id)),
+            completed: plan.completed,
+        };
+        journal.save(plan, None)?;
+        Ok(journal)
+    }
+
+    pub(crate) fn complete(&mut self, plan: &Plan) -> Result<()> {
+        self.completed += 1;
+        self.save(plan, None)
+    }
+
+    pub(crate) fn fail(&self, plan: &Plan, err: &anyhow::Error) -> Result<()> {
+        self.save(plan, Some(&format!("{:#}", err)))
+    }
+
+    fn save(&self, plan: &Plan, error: Option<&str>) -> Result<()> {
+        let record = serde_json::json!({
+            "plan": plan.to_json(),
+            "completed": self.completed,
+            "error": error,
+            "preconditions": Precondition::for_plan(plan)?,
+        });
+        write_atomically(&self.path, &serde_json::to_vec_pretty(&record)?)
+    }
+}
+
+// Removes the records of plans older than `PLAN_MAX_AGE`.
+fn prune(dir: &Utf8Path, now: SystemTime) -> Result<()> {
+    for id in record_ids(dir)? {
+        if now
+            .duration_since(id.datetime())
+            .is_ok_and(|age| age > PLAN_MAX_AGE)
+        {
+            let path = dir.join(format!("{}.json", id));
+            std::fs::remove_file(&path).with_context(|| format!("failed to remove {}", path))?;
+        }
+    }
+    Ok(())
+}
+
+// The IDs of the plans recorded in `dir`, oldest first.
+fn record_ids(dir: &Utf8Path) -> Result<Vec<Ulid>> {
+    let mut ids = match dir.read_dir_utf8() {
+        Ok(entries) => entries
+            .filter_map(|entry| entry.ok()?.file_name().strip_suffix(".json")?.parse().ok())
+            .collect::<Vec<Ulid>>(),
+        Err(err) if err.kind() == ErrorKind::NotFound => Vec::new(),
+        Err(err) => return Err(err).with_context(|| format!("failed to read {}", dir)),
+    };
+    // ULIDs sort by creation time.
+    ids.sort_unstable();
+    Ok(ids)
+}
+
+// A `StepJournal` as read back.
+#[derive(Debug, Deserialize)]
+pub(crate) struct PlanRecord {
+    pub(crate) plan: Plan,
+    pub(crate) completed: usize,
+    pub(crate) error: Option<String>,
+    // Not recorded by older versions
+    #[serde(default)]
+    pub(crate) preconditions: Vec<Precondition>,
+}
+
+impl PlanRecord {
+    pub(crate) fn load(id: Ulid) -> Result<PlanRecord> {
+        PlanRecord::load_from(&plans_dir()?, id)
+    }
+
+    fn load_from(dir: &Utf8Path, id: Ulid) -> Result<PlanRecord> {
+        let path = dir.join(format!("{}.json", id));
+        let file = match std::fs::read_to_string(&path) {
+            Ok(file) => file,
+            Err(err) if err.kind() == ErrorKind::NotFound => {
+                anyhow::bail!("no plan {} in {}", id, dir);
+            }
+            Err(err) => return Err(err).with_context(|| format!("failed to read {}", path)),
+        };
+        serde_json::from_str(&file).with_context(|| format!("failed to parse {}", path))
+    }
+
+    // All journaled plans, oldest first. Records that can't be read (from another version, say)
+    // are skipped with a warning.
+    pub(crate) fn load_all() -> Result<Vec<PlanRecord>> {
+        PlanRecord::load_all_from(&plans_dir()?)
+    }
+
+    fn load_all_from(dir: &Utf8Path) -> Result<Vec<PlanRecord>> {
+        Ok(record_ids(dir)?
+            .into_iter()
+            .filter_map(|id| match PlanRecord::load_from(dir, id) {
+                Ok(record) => Some(record),
+                Err(err) => {
+                    eprintln!("{} skipping plan {}: {:#}", style("==>").yellow(), id, err);
+                    None
+                }
+            })
+            .collect())
+    }
+
+    pub(crate) fn is_finished(&self) -> bool {
+        self.completed >= self.plan.steps.len()
+    }
+
+    fn status(&self) -> String {
+        match &self.error {
+            _ if self.is_finished() => "finished".to_owned(),
+            Some(error) => format!("failed: {}", error),
+            None => "incomplete".to_owned(),
+        }
+    }
+}
+
+// The datasets used by plans that haven't finished, which could still be resumed, and which
+// plan uses each (the most recent, if more than one does).
+pub(crate) fn unfinished_datasets() -> Result<BTreeMap<String, Ulid>> {
+    let mut datasets = BTreeMap::new();
+    for record in PlanRecord::load_all()? {
+        if !record.is_finished() {
+            for step in &record.plan.steps {
+                for dataset in step.datasets() {
+                    datasets.insert(dataset.to_owned(), record.plan.id);
+                }
+            }
+        }
+    }
+    Ok(datasets)
+}
+
+// Forgets an unfinished plan, so that it can't be resumed and the next plan cleans up after it.
+pub(crate) fn discard(id: Ulid) -> Result<()> {
+    discard_in(&plans_dir()?, id)
+}
+
+fn discard_in(dir: &Utf8Path, id: Ulid) -> Result<()> {
+    let record = PlanRecord::load_from(dir, id)?;
+    ensure!(!record.is_finished(), "plan {} already finished", id);
+    // A prepared plan that stopped may have left things in place for running the script by hand.
+    for leftover in record
+        .plan
+        .prepared
+        .iter()
+        .flat_map(|prepared| &prepared.leftovers)
+        .filter(|path| path.exists())
+    {
+        record
+            .plan
+            .escalate
+            .command("rm")
+            .args(["-rf", "--"])
+            .arg(leftover)
+            .succeed()?;
+        eprintln!("{} removed {}", style("==>").blue(), leftover);
+    }
+    let path = dir.join(format!("{}.json", id));
+    std::fs::remove_file(&path).with_context(|| format!("failed to remove {}", path))?;
+    eprintln!(
+        "{} discarded plan {}; the next plan will remove the datasets it didn't finish setting up",
+        style("==>").blue(),
+        id
+    );
+    Ok(())
+}
+
+// Lists the journaled plans, or shows the steps of one and how far it got.
+pub(crate) fn inspect(id: Option<Ulid>) -> Result<()> {
+    if let Some(id) = id {
+        return show_plan(id);
+    }
+    for record in PlanRecord::load_all()? {
+        println!(
+            "{}  {}  {}/{} steps  {}",
+            record.plan.id,
+            humantime::format_rfc3339_seconds(record.plan.id.datetime()),
+            record.completed,
+            record.plan.steps.len(),
+            record.status()
+        );
+    }
+    Ok(())
+}
+
+fn show_plan(id: Ulid) -> Result<()> {
+    let record = PlanRecord::load(id)?;
+    println!(
+        "plan {} ({}): {}",
+        record.plan.id,
+        humantime::format_rfc3339_seconds(record.plan.id.datetime()),
+        record.status()
+    );
+    for (i, step) in record.plan.steps.iter().enumerate() {
+        let mark = if let Step::Comment(_) = step {
+            " ".to_owned()
+        } else if i < record.completed {
+            style("✓").green().to_string()
+        } else if i == record.completed && record.error.is_some() {
+            style("✗").red().to_string()
+        } else {
+            style("·").dim().to_string()
+        };
+        for (j, line) in step
+            .commands_for_approval(record.plan.escalate)
+            .into_iter()
+            .enumerate()
+        {
+            println!("{} {}", if j == 0 { &mark } else { " " }, line);
+        }
+    }
+    if !record.is_finished() {
+        println!(
+            "run `buildomat-at-home --resume={}` to carry on from the first step not marked done, \
+             or `buildomat-at-home discard {}` to give up on it",
+            record.plan.id, record.plan.id
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+#[test]
+fn test_incomplete_datasets() {
+    use crate::step;
+
+    let tempdir = tempfile::tempdir().unwrap();
+    let path = Utf8PathBuf::try_from(tempdir.path().join("journal")).unwrap();
+    let mut journal = Journal::load_from(path.clone()).unwrap();
+    assert!(journal.incomplete().is_empty());
+
+    let input = "rpool/buildomat-at-home/github/oxidecomputer/omicron/14561963408";
+    let work = step::isolation().dataset;
+    let create = |dataset: &str| Step::CreateDataset {
+        dataset: dataset.into(),
+        mountpoint: None,
+        create_parents: true,
+        chown: "me:staff".into(),
+        quota: None,
+    };
+    let run_script = step::run_script(Some(step::isolation()));
+
+    // The run is interrupted while downloading artefacts.
+    journal.starting(&create(input)).unwrap();
+    journal.finished(&create(input)).unwrap();
+    assert_eq!(
+        Journal::load_from(path.clone()).unwrap().incomplete(),
+        &BTreeSet::from([input.to_owned()])
+    );
+
+    // The next run recreates it and carries on until the job fails.
+    let mut journal = Journal::load_from(path.clone()).unwrap();
+    for step in [
+        create(input),
+        Step::SetDatasetReadOnly {
+            dataset: input.into(),
+        },
+        create(&work),
+    ] {
+        journal.starting(&step).unwrap();
+        journal.finished(&step).unwrap();
+    }
+    assert_eq!(journal.incomplete(), &BTreeSet::from([work.clone()]));
+    journal.starting(&run_script).unwrap();
+    assert!(Journal::load_from(path).unwrap().incomplete().is_empty());
+}
+
+#[cfg(test)]
+#[test]
+fn test_plan_records() {
+    use crate::command::Escalate;
+
+    let tempdir = tempfile::tempdir().unwrap();
+    let dir = Utf8Path::from_path(tempdir.path()).unwrap();
+    let now = SystemTime::now();
+    let plan = |id| Plan {
+        id,
+        escalate: Escalate::None,
+        steps: vec![
+            Step::Comment("create rpool/buildomat-at-home".into()),
+            Step::Comment("run job script".into()),
+        ],
+        shell_on_failure: false,
+        prepared: None,
+        private_dir: None,
+        inputs: Vec::new(),
+        source: None,
+        completed: 0,
+        resumed: false,
+    };
+
+    // Old records are pruned when the next plan starts.
+    let old = Ulid::from_datetime(now - PLAN_MAX_AGE - Duration::from_secs(1));
+    StepJournal::create_in(dir, &plan(old), now - PLAN_MAX_AGE).unwrap();
+    // A record from some other version can't be read.
+    let unreadable = Ulid::from_datetime(now);
+    std::fs::write(dir.join(format!("{}.json", unreadable)), "{}").unwrap();
+    let id = Ulid::from_datetime(now);
+    let plan = plan(id);
+    let mut journal = StepJournal::create_in(dir, &plan, now).unwrap();
+    assert!(PlanRecord::load_from(dir, old).is_err());
+    let records = PlanRecord::load_all_from(dir).unwrap();
+    assert_eq!(records.len(), 1);
+    assert_eq!(records[0].plan.id, id);
+    assert!(!records[0].is_finished());
+    assert_eq!(records[0].status(), "incomplete");
+
+    journal.complete(&plan).unwrap();
+    journal.fail(&plan, &anyhow::anyhow!("boom")).unwrap();
+    let record = PlanRecord::load_from(dir, id).unwrap();
+    assert_eq!(record.completed, 1);
+    assert_eq!(record.status(), "failed: boom");
+    assert!(record.preconditions.is_empty());
+
+    journal.complete(&plan).unwrap();
+    let record = PlanRecord::load_from(dir, id).unwrap();
+    assert!(record.is_finished());
+    assert_eq!(record.status(), "finished");
+}
+
+#[cfg(test)]
+#[test]
+fn test_discard_prepared() {
+    use crate::command::Escalate;
+
+    let tempdir = tempfile::tempdir().unwrap();
+    let dir = Utf8Path::from_path(tempdir.path()).unwrap();
+    let home = dir.join("home");
+    std::fs::create_dir(&home).unwrap();
+    std::fs::write(home.join(".netrc"), "password").unwrap();
+    let plan = Plan {
+        id: Ulid::new(),
+        escalate: Escalate::None,
+        steps: vec![Step::Comment("create rpool/buildomat-at-home".into())],
+        shell_on_failure: false,
+        prepared: Some(
+            serde_json::from_value(serde_json::json!({
+                "prepare": "Exit",
+                "script": crate::step::run_script(None),
+                "id": Ulid::new(),
+                "leftovers": [home, dir.join("gone")],
+            }))
+            .unwrap(),
+        ),
+        private_dir: None,
+        inputs: Vec::new(),
+        source: None,
+        completed: 0,
+        resumed: false,
+    };
+    StepJournal::create_in(dir, &plan, SystemTime::now()).unwrap();
+    discard_in(dir, plan.id).unwrap();
+    assert!(!home.exists());
+    assert!(PlanRecord::load_from(dir, plan.id).is_err());
+}
@@ -0,0 +1,256 @@
+use anyhow::Result;
+use indicatif::HumanBytes;
+use serde::{Deserialize, Serialize};
+use std::process::Command;
+
+#[cfg(target_os = "linux")]
+pub(crate) use linux::Cgroup;
+
+#[cfg(not(target_os = "linux"))]
+#[derive(Debug)]
+pub(crate) enum Cgroup {}
+
+#[cfg(not(target_os = "linux"))]
+impl Cgroup {
+    pub(crate) fn oom_kills(&self) -> Result<u64> {
+        match *self {}
+    }
+
+    pub(crate) fn pids(&self) -> Result<Vec<libc::pid_t>> {
+        match *self {}
+    }
+}
+
+// Resource limits for the job script, to mirror the size of the instance Buildomat would run it
+// on. The memory limit is enforced by a cgroup (Linux only), the CPU count by restricting the
+// script's CPU affinity (Linux only, and what `nproc` reports), and the open file limit by
+// `RLIMIT_NOFILE`.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub(crate) struct Limits {
+    pub(crate) memory: Option<u64>,
+    pub(crate) cpus: Option<usize>,
+    pub(crate) open_files: Option<u64>,
+}
+
+impl Limits {
+    pub(crate) fn describe(&self) -> Vec<String> {
+        let mut lines = Vec::new();
+        if let Some(memory) = self.memory {
+            lines.push(format!("memory = {}", HumanBytes(memory)));
+        }
+        if let Some(cpus) = self.cpus {
+            lines.push(format!("cpus = {}", cpus));
+        }
+        if let Some(open_files) = self.open_files {
+            lines.push(format!("open files = {}", open_files));
+        }
+        lines
+    }
+
+    // Must be called before `Isolation::apply`, so that the script joins its cgroup before
+    // entering a new user namespace. The returned cgroup is removed when it's dropped.
+    #[cfg(target_os = "linux")]
+    pub(crate) fn apply(&self, command: &mut Command) -> Result<Option<Cgroup>> {
+        use std::ffi::CString;
+        use std::io;
+        use std::os::unix::process::CommandExt;
+
+        let cgroup = self.memory.map(Cgroup::create).transpose()?;
+        let procs = cgroup
+            .as_ref()
+            .map(|cgroup| CString::new(cgroup.dir.join("cgroup.procs").as_str()))
+            .transpose()?;
+        let cpus = self.cpus.map(linux::cpu_set).transpose()?;
+        let open_files = self.open_files;
+
+        // SAFETY: this only makes system calls using memory allocated before the fork.
+        unsafe {
+            command.pre_exec(move || {
+                if let Some(procs) = &procs {
+                    // Writing 0 moves the writing process.
+                    let fd = libc::open(procs.as_ptr(), libc::O_WRONLY | libc::O_CLOEXEC);
+                    if fd == -1 {
+                        return Err(io::Error::last_os_error());
+                    }
+                    let written = libc::write(fd, b"0".as_ptr().cast(), 1);
+                    let err = io::Error::last_os_error();
+                    libc::close(fd);
+                    if written == -1 {
+                        return Err(err);
+                    }
+                }
+                if let Some(cpus) = &cpus {
+                    if libc::sched_setaffinity(0, std::mem::size_of_val(cpus), cpus) == -1 {
+                        return Err(io::Error::last_os_error());
+                    }
+                }
+                if let Some(open_files) = open_files {
+                    let limit = libc::rlimit {
+                        rlim_cur: open_files,
+                        rlim_max: open_files,
+                    };
+                    if libc::setrlimit(libc::RLIMIT_NOFILE, std::ptr::addr_of!(limit)) == -1 {
+                        return Err(io::Error::last_os_error());
+                    }
+                }
+                Ok(())
+            });
+        }
+        Ok(cgroup)
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub(crate) fn apply(&self, command: &mut Command) -> Result<Option<Cgroup>> {
+        use std::os::unix::process::CommandExt;
+
+        anyhow::ensure!(
+            self.memory.is_none() && self.cpus.is_none(),
+            "memory and CPU limits are only supported on Linux"
+        );
+        if let Some(open_files) = self.open_files {
+            // SAFETY: `setrlimit` is async-signal-safe.
+            unsafe {
+                command.pre_exec(move || {
+                    let limit = libc::rlimit {
+                        rlim_cur: open_files as libc::rlim_t,
+                        rlim_max: open_files as libc::rlim_t,
+                    };
+                    if libc::setrlimit(libc::RLIMIT_NOFILE, std::ptr::addr_of!(limit)) == -1 {
+                        return Err(std::io::Error::last_os_error());
+                    }
+                    Ok(())
+                });
+            }
+        }
+        Ok(None)
+    }
+}
+
+#[cfg(target_os = "linux")]
+pub(crate) mod linux {
+    use anyhow::{bail, ensure, Context, Result};
+    use camino::{Utf8Path, Utf8PathBuf};
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    const CGROUP_ROOT: &str = "/sys/fs/cgroup";
+    // Distinguishes the cgroups we create.
+    static NEXT_ID: AtomicU32 = AtomicU32::new(0);
+
+    #[derive(Debug)]
+    pub(crate) struct Cgroup {
+        pub(super) dir: Utf8PathBuf,
+    }
+
+    impl Cgroup {
+        pub(super) fn create(memory: u64) -> Result<Cgroup> {
+            ensure!(
+                Utf8Path::new(CGROUP_ROOT)
+                    .join("cgroup.controllers")
+                    .exists(),
+                "memory limits require cgroups v2"
+            );
+            let own = std::fs::read_to_string("/proc/self/cgroup")?
+                .lines()
+                .find_map(|line| line.strip_prefix("0::").map(ToOwned::to_owned))
+                .context("failed to find our cgroup")?;
+            // A cgroup with processes in it can't enable controllers for its children, so the
+            // job's cgroup is a sibling of ours (usually in a slice delegated to us by systemd).
+            let parent = Utf8Path::new(CGROUP_ROOT)
+                .join(own.trim_start_matches('/'))
+                .parent()
+                .context("can't create a cgroup beside the root cgroup")?
+                .to_owned();
+            let controllers = std::fs::read_to_string(parent.join("cgroup.subtree_control"))?;
+            if !controllers.split_whitespace().any(|c| c == "memory") {
+                bail!(
+                    "the memory controller isn't enabled for {}; try running under \
+                    `systemd-run --user --scope`",
+                    parent
+                );
+            }
+
+            // The job script and a debug shell each get their own, and the script's may still
+            // exist (with processes left in it) when the shell starts.
+            let dir = parent.join(format!(
+                "buildomat-at-home-{}-{}",
+                std::process::id(),
+                NEXT_ID.fetch_add(1, Ordering::Relaxed)
+            ));
+            std::fs::create_dir(&dir).with_context(|| {
+                format!(
+                    "failed to create cgroup {}; try running under `systemd-run --user --scope`",
+                    dir
+                )
+            })?;
+            let cgroup = Cgroup { dir };
+            std::fs::write(cgroup.dir.join("memory.max"), memory.to_string())?;
+            // Without swap, the limit behaves like an instance's RAM. This file doesn't exist if
+            // swap accounting is disabled, in which case there's nothing to turn off.
+            std::fs::write(cgroup.dir.join("memory.swap.max"), "0").ok();
+            Ok(cgroup)
+        }
+
+        // The number of processes killed for exceeding the memory limit.
+        pub(crate) fn oom_kills(&self) -> Result<u64> {
+            let events = std::fs::read_to_string(self.dir.join("memory.events"))?;
+            Ok(events
+                .lines()
+                .find_map(|line| line.strip_prefix("oom_kill "))
+                .map_or(Ok(0), str::parse)?)
+        }
+    }
+
+    impl Cgroup {
+        pub(crate) fn pids(&self) -> Result<Vec<libc::pid_t>> {
+            Ok(std::fs::read_to_string(self.dir.join("cgroup.procs"))?
+                .lines()
+                .map(str::parse)
+                .collect::<Result<_, _>>()?)
+        }
+    }
+
+    impl Drop for Cgroup {
+        fn drop(&mut self) {
+            // This fails if anything the script started is still running.
+            std::fs::remove_dir(&self.dir).ok();
+        }
+    }
+
+    // The first `count` CPUs we're allowed to run on.
+    pub(super) fn cpu_set(count: usize) -> Result<libc::cpu_set_t> {
+        // SAFETY: `cpu_set_t` is plain data, and the `CPU_*` functions are given valid sets and
+        // CPU numbers below `CPU_SETSIZE`.
+        unsafe {
+            let mut current: libc::cpu_set_t = std::mem::zeroed();
+            if libc::sched_getaffinity(
+                0,
+                std::mem::size_of_val(&current),
+                std::ptr::addr_of_mut!(current),
+            ) == -1
+            {
+                return Err(std::io::Error::last_os_error().into());
+            }
+            let available = libc::CPU_COUNT(&current);
+            ensure!(count > 0, "CPU limit must be at least 1");
+            ensure!(
+                usize::try_from(available)? >= count,
+                "limit of {} CPUs is more than the {} available",
+                count,
+                available
+            );
+
+            let mut set: libc::cpu_set_t = std::mem::zeroed();
+            let mut chosen = 0;
+            for cpu in 0..usize::try_from(libc::CPU_SETSIZE)? {
+                if chosen == count {
+                    break;
+                }
+                if libc::CPU_ISSET(cpu, &current) {
+                    libc::CPU_SET(cpu, &mut set);
+                    chosen += 1;
+                }
+            }
+            Ok(set)
+        }
+    }
+}
@@ -0,0 +1,129 @@
+use crate::state_dir;
+use anyhow::{Context, Result};
+use camino::{Utf8Path, Utf8PathBuf};
+use std::fs::File;
+use std::io::{BufRead, BufReader, Write};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+use ulid::Ulid;
+
+// Each line of a job log is `TIMESTAMP STREAM | LINE`, where STREAM is `stdout` or `stderr` for
+// the script's output, or `status` for the outcome of the run (always the last line).
+const STATUS: &str = "status";
+
+pub(crate) fn path(id: Ulid) -> Result<Utf8PathBuf> {
+    Ok(state_dir()?.join("logs").join(format!("{}.log", id)))
+}
+
+#[derive(Debug)]
+pub(crate) struct JobLog {
+    // `None` once the status line is written. Processes the script left running can still be
+    // writing to its stdout and stderr, but nothing more is logged.
+    file: Mutex<Option<File>>,
+}
+
+impl JobLog {
+    pub(crate) fn create(path: &Utf8Path) -> Result<JobLog> {
+        let parent = path.parent().expect("log path must have parent directory");
+        std::fs::create_dir_all(parent)?;
+        let file = File::create(path).with_context(|| format!("failed to create {}", path))?;
+        Ok(JobLog {
+            file: Mutex::new(Some(file)),
+        })
+    }
+
+    pub(crate) fn record(&self, stream: &str, line: &[u8]) -> std::io::Result<()> {
+        match &mut *self.file.lock().unwrap() {
+            Some(file) => write_line(file, stream, line),
+            None => Ok(()),
+        }
+    }
+
+    // Writes the status line and closes the log, so that the status line is always the last.
+    pub(crate) fn status(&self, status: &str) -> std::io::Result<()> {
+        match self.file.lock().unwrap().take() {
+            Some(mut file) => write_line(&mut file, STATUS, status.as_bytes()),
+            None => Ok(()),
+        }
+    }
+}
+
+fn write_line(file: &mut File, stream: &str, line: &[u8]) -> std::io::Result<()> {
+    let line = String::from_utf8_lossy(line);
+    writeln!(
+        file,
+        "{} {} | {}",
+        humantime::format_rfc3339_millis(SystemTime::now()),
+        stream,
+        line.trim_end_matches(['\r', '\n'])
+    )
+}
+
+// Prints a job's log (the most recent job's if `id` is `None`). If `follow` is set, keeps
+// printing new lines until the job finishes.
+pub(crate) fn show(id: Option<Ulid>, follow: bool) -> Result<()> {
+    let path = if let Some(id) = id {
+        path(id)?
+    } else {
+        let dir = state_dir()?.join("logs");
+        // ULIDs sort by creation time.
+        dir.read_dir_utf8()
+            .with_context(|| format!("failed to read {}", dir))?
+            .filter_map(|entry| Some(entry.ok()?.into_path()))
+            .filter(|path| path.extension() == Some("log"))
+            .max()
+            .context("no job logs found")?
+    };
+    let mut reader =
+        BufReader::new(File::open(&path).with_context(|| format!("failed to open {}", path))?);
+
+    let mut stdout = std::io::stdout().lock();
+    let mut line = String::new();
+    loop {
+        // `read_line` appends, so a partially-written line is completed by later reads.
+        reader.read_line(&mut line)?;
+        if line.ends_with('\n') {
+            stdout.write_all(line.as_bytes())?;
+            stdout.flush()?;
+            let is_status = line
+                .split_once(' ')
+                .is_some_and(|(_, rest)| rest.starts_with(STATUS));
+            if follow && is_status {
+                return Ok(());
+            }
+            line.clear();
+        } else if follow {
+            std::thread::sleep(Duration::from_millis(250));
+        } else {
+            stdout.write_all(line.as_bytes())?;
+            return Ok(());
+        }
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn test_status_is_last() {
+    let tempdir = tempfile::tempdir().unwrap();
+    let path = Utf8PathBuf::try_from(tempdir.path().join("job.log")).unwrap();
+    let log = JobLog::create(&path).unwrap();
+    log.record("stdout", b"building\n").unwrap();
+    log.record("stderr", b"warning: unused\r\n").unwrap();
+    log.status("exited with exit status: 0").unwrap();
+    // A process the script left running, writing after it exited.
+    log.record("stdout", b"still here\n").unwrap();
+
+    let contents = std::fs::read_to_string(&path).unwrap();
+    let lines = contents
+        .lines()
+        .map(|line| line.split_once(' ').unwrap().1)
+        .collect::<Vec<_>>();
+    assert_eq!(
+        lines,
+        [
+            "stdout | building",
+            "stderr | warning: unused",
+            "status | exited with exit status: 0",
+        ]
+    );
+}
@@ -2,23 +2,269 @@
 #![allow(
     clippy::manual_let_else, // rust-lang/rustfmt#4914
     clippy::too_many_lines,
+    clippy::unsafe_derive_deserialize, // our `unsafe` is FFI, which doesn't rely on invariants
     clippy::uninlined_format_args, // rust-lang/rust-analyzer#11260
 )]
 
+mod cache;
 mod command;
+mod config;
 mod input;
+mod interrupt;
+mod isolate;
+mod job;
+mod journal;
+mod limits;
+mod log;
 mod plan;
+mod saved;
 mod step;
+mod toolchain;
+mod user;
 
-use anyhow::{bail, Context, Result};
-use camino::Utf8PathBuf;
+use anyhow::{ensure, Context, Result};
+use camino::{Utf8Component, Utf8Path, Utf8PathBuf};
+use clap::Parser;
 use reqwest::Client;
 use std::process::ExitCode;
-use std::str::FromStr;
+use std::time::Duration;
+use ulid::Ulid;
 
 const POOL: &str = "rpool";
 const OUR_DATASET: &str = "rpool/buildomat-at-home";
 const JOB_NAME_PROPERTY: &str = "computer.oxide.eng.buildomat-at-home:job_name";
+// Non-default variables the job ran with, if any: `KEY=VALUE` for those set explicitly, and `KEY`
+// for those passed through from our environment.
+const ENV_PROPERTY: &str = "computer.oxide.eng.buildomat-at-home:env";
+// Set on a work dataset by `prepare` to the ID it will be saved as by `finish`.
+const PREPARED_PROPERTY: &str = "computer.oxide.eng.buildomat-at-home:prepared";
+const ARTEFACT_CACHE_SIZE: u64 = 20 << 30;
+// Exit status when a plan isn't run because there was no terminal to ask for approval on, as
+// opposed to 1 when it's refused (or fails).
+const NOT_ASKED: u8 = 3;
+// Host directory under which isolated jobs' datasets are mounted, and fresh home directories are
+// created.
+const ISOLATED_ROOT: &str = "/var/tmp/buildomat-at-home";
+
+#[derive(Debug, Parser)]
+#[command(
+    version,
+    args_conflicts_with_subcommands = true,
+    subcommand_negates_reqs = true
+)]
+struct Args {
+    #[command(subcommand)]
+    command: Option<Subcommand>,
+    #[command(flatten)]
+    run: RunArgs,
+    #[command(flatten)]
+    approval: ApprovalArgs,
+}
+
+#[derive(Debug, clap::Subcommand)]
+#[allow(clippy::large_enum_variant)] // only one is ever parsed
+enum Subcommand {
+    /// Print the output log of a job run
+    Log {
+        /// The run's ID (or the `local/ULID` input it was saved as); defaults to the most recent
+        #[arg(value_parser = parse_run_id)]
+        id: Option<Ulid>,
+        /// Keep printing output as it's written, until the job finishes
+        #[arg(short, long)]
+        follow: bool,
+    },
+    /// Set up /work and /input for a job, but don't run its script
+    Prepare {
+        #[command(flatten)]
+        run: RunArgs,
+        /// Start an interactive shell in the job's environment once everything is set up
+        #[arg(long)]
+        shell: bool,
+        #[command(flatten)]
+        approval: ApprovalArgs,
+    },
+    /// Build a job's plan and save it to a file, to review and run later with `apply`
+    Plan {
+        #[command(flatten)]
+        run: RunArgs,
+        /// Where to save the plan
+        #[arg(long, value_name = "PATH")]
+        out: Utf8PathBuf,
+    },
+    /// List the plans that have run, or show how far one got
+    Inspect {
+        /// The plan's ID; without one, all plans are listed
+        #[arg(value_parser = parse_run_id)]
+        id: Option<Ulid>,
+    },
+    /// Give up on a plan that failed or was interrupted, so that it can't be resumed and the
+    /// next plan cleans up the datasets it didn't finish setting up
+    Discard {
+        /// The plan's ID
+        #[arg(value_parser = parse_run_id)]
+        id: Ulid,
+    },
+    /// Run a plan saved by `plan`, if nothing it depends on has changed since
+    Apply {
+        /// The saved plan
+        path: Utf8PathBuf,
+        #[command(flatten)]
+        approval: ApprovalArgs,
+    },
+    /// Save a prepared job's /work as an input
+    Finish {
+        /// The prepared run's ID; only needed if more than one job is prepared
+        #[arg(value_parser = parse_run_id)]
+        id: Option<Ulid>,
+        /// How to run privileged commands (`zfs`, `chown`); detected from PATH if not specified.
+        /// Use `none` if running as root or with delegated ZFS permissions
+        #[arg(long, value_name = "STRATEGY")]
+        escalate: Option<command::Escalate>,
+        #[command(flatten)]
+        approval: ApprovalArgs,
+    },
+}
+
+#[derive(Debug, clap::Args)]
+struct ApprovalArgs {
+    /// Run the plan without asking for approval
+    #[arg(short, long)]
+    yes: bool,
+    /// Print the plan and exit without running it
+    #[arg(long, conflicts_with = "yes")]
+    dry_run: bool,
+    /// How to print the plan: `text` (on stderr) or `json` (on stdout)
+    #[arg(long, value_name = "FORMAT", default_value = "text")]
+    plan_format: plan::PlanFormat,
+}
+
+impl ApprovalArgs {
+    fn approval(&self) -> plan::Approval {
+        if self.dry_run {
+            plan::Approval::DryRun
+        } else if self.yes {
+            plan::Approval::Yes
+        } else {
+            plan::Approval::Prompt
+        }
+    }
+}
+
+#[derive(Debug, clap::Args)]
+#[allow(clippy::struct_excessive_bools)]
+struct RunArgs {
+    /// Job script, in `.github/buildomat/jobs`
+    #[arg(required_unless_present = "resume")]
+    script: Option<Utf8PathBuf>,
+    /// Inputs for the job, either `local/ULID` or a GitHub check run URL
+    inputs: Vec<input::Input>,
+    /// Run the job script in unprivileged user and mount namespaces (Linux only), with this
+    /// job's work and input datasets bind-mounted at /work and /input
+    #[arg(long)]
+    isolate: bool,
+    /// Run the job script chrooted into a root filesystem from an image tarball or directory
+    /// (implies --isolate); without a path, use the image configured for the job's target
+    #[allow(clippy::option_option)] // clap's idiom for an option with an optional value
+    #[arg(long, value_name = "PATH", require_equals = true)]
+    image: Option<Option<Utf8PathBuf>>,
+    /// How to run privileged commands (`zfs`, `chown`); detected from PATH if not specified. Use
+    /// `none` if running as root or with delegated ZFS permissions
+    #[arg(long, value_name = "STRATEGY")]
+    escalate: Option<command::Escalate>,
+    /// Set a variable for the job script
+    #[arg(long, value_name = "KEY=VALUE", value_parser = parse_env)]
+    env: Vec<(String, String)>,
+    /// Pass a variable through from this environment to the job script
+    #[arg(long, value_name = "KEY", value_parser = parse_env_name)]
+    pass_env: Vec<String>,
+    /// Terminate the job script if it runs for longer than this (e.g. `90m`); overrides the
+    /// job's target's default timeout
+    #[arg(long, value_name = "DURATION", value_parser = humantime::parse_duration)]
+    timeout: Option<Duration>,
+    /// If the job script fails, start an interactive shell in its environment, then ask whether
+    /// to save /work as an input
+    #[arg(long)]
+    shell_on_failure: bool,
+    /// Run the job script as a local build user (not with --isolate), which /work is given to;
+    /// without a user, use the one configured as `build_user`
+    #[allow(clippy::option_option)] // clap's idiom for an option with an optional value
+    #[arg(long, value_name = "USER", require_equals = true)]
+    build_user: Option<Option<String>>,
+    /// Give the job script a fresh home directory, with only the paths configured in `home.link`
+    /// linked from the real one
+    #[arg(long)]
+    fresh_home: bool,
+    /// Keep the fresh home directory after the job script succeeds
+    #[arg(long, requires = "fresh_home")]
+    keep_home: bool,
+    /// Leave processes the job script started in the background running after it exits, rather
+    /// than terminating them
+    #[arg(long)]
+    no_reap: bool,
+    /// Leave untracked files out of the clone of the repository in /work; by default, untracked
+    /// files that aren't ignored are included, along with uncommitted changes
+    #[arg(long)]
+    no_untracked: bool,
+    /// The GitHub repository the job is for, which sets where it's cloned in /work and
+    /// `GITHUB_REPOSITORY`; found from the URL of the remote by default
+    #[arg(long, value_name = "OWNER/NAME", value_parser = parse_repo)]
+    repo: Option<(String, String)>,
+    /// The remote to find the GitHub repository from; defaults to the configured `remote`, or
+    /// `origin`
+    #[arg(long, value_name = "NAME", conflicts_with = "repo")]
+    remote: Option<String>,
+    /// Clone the repository at this commit, branch or tag, and run the job script as it is
+    /// there, instead of using the working tree
+    #[arg(long, value_name = "COMMIT-ISH", conflicts_with = "no_untracked")]
+    rev: Option<String>,
+    /// Resume a plan that failed or was interrupted, skipping the steps that completed and
+    /// re-running the one that didn't; without an ID, resume the most recent unfinished plan
+    #[allow(clippy::option_option)] // clap's idiom for an option with an optional value
+    #[arg(
+        long,
+        value_name = "ID",
+        require_equals = true,
+        value_parser = parse_run_id,
+        conflicts_with_all = ["script", "inputs"]
+    )]
+    resume: Option<Option<Ulid>>,
+}
+
+fn parse_run_id(s: &str) -> Result<Ulid, ulid::DecodeError> {
+    s.strip_prefix("local/").unwrap_or(s).parse()
+}
+
+fn parse_repo(s: &str) -> Result<(String, String), String> {
+    s.split_once('/')
+        .filter(|(owner, name)| !owner.is_empty() && !name.is_empty() && !name.contains('/'))
+        .map(|(owner, name)| (owner.to_owned(), name.to_owned()))
+        .ok_or_else(|| format!("`{}` is not in the form OWNER/NAME", s))
+}
+
+fn parse_env(s: &str) -> Result<(String, String), String> {
+    let (key, value) = s
+        .split_once('=')
+        .ok_or_else(|| format!("`{}` is not in the form KEY=VALUE", s))?;
+    Ok((parse_env_name(key)?, value.to_owned()))
+}
+
+fn parse_env_name(s: &str) -> Result<String, String> {
+    if is_env_name(s) {
+        Ok(s.to_owned())
+    } else {
+        Err(format!(
+            "`{}` is not a variable name (letters, digits and `_`, not starting with a digit)",
+            s
+        ))
+    }
+}
+
+// Whether `s` can be a variable name in the shell.
+fn is_env_name(s: &str) -> bool {
+    !s.is_empty()
+        && !s.starts_with(|c: char| c.is_ascii_digit())
+        && s.chars().all(|c| c == '_' || c.is_ascii_alphanumeric())
+}
 
 #[tokio::main]
 async fn main() -> Result<ExitCode> {
@@ -26,25 +272,278 @@ async fn main() -> Result<ExitCode> {
         .user_agent("https://github.com/oxidecomputer/buildomat-at-home")
         .build()?;
 
-    let mut args = std::env::args().skip(1);
-    let script = match args.next() {
-        Some(x) => Utf8PathBuf::from(x)
+    let (plan, approval, format) = match Args::parse() {
+        Args {
+            command: Some(Subcommand::Log { id, follow }),
+            ..
+        } => {
+            log::show(id, follow)?;
+            return Ok(ExitCode::SUCCESS);
+        }
+        Args {
+            command: Some(Subcommand::Inspect { id }),
+            ..
+        } => {
+            journal::inspect(id)?;
+            return Ok(ExitCode::SUCCESS);
+        }
+        Args {
+            command: Some(Subcommand::Discard { id }),
+            ..
+        } => {
+            journal::discard(id)?;
+            return Ok(ExitCode::SUCCESS);
+        }
+        Args {
+            command:
+                Some(Subcommand::Prepare {
+                    run,
+                    shell,
+                    approval,
+                }),
+            ..
+        } => {
+            ensure!(
+                run.resume.is_none(),
+                "--resume can't be used with `prepare`"
+            );
+            let prepare = if shell {
+                plan::Prepare::Shell
+            } else {
+                plan::Prepare::Exit
+            };
+            (
+                build_plan(&client, run, Some(prepare)).await?,
+                approval.approval(),
+                approval.plan_format,
+            )
+        }
+        Args {
+            command: Some(Subcommand::Plan { run, out }),
+            ..
+        } => {
+            ensure!(run.resume.is_none(), "--resume can't be used with `plan`");
+            let plan = build_plan(&client, run, None).await?;
+            saved::save(&plan, &out)?;
+            eprintln!(
+                "saved the plan to {}; run it with `buildomat-at-home apply {}`",
+                out, out
+            );
+            return Ok(ExitCode::SUCCESS);
+        }
+        Args {
+            command: Some(Subcommand::Apply { path, approval }),
+            ..
+        } => (
+            saved::load(&path)?,
+            approval.approval(),
+            approval.plan_format,
+        ),
+        Args {
+            command:
+                Some(Subcommand::Finish {
+                    id,
+                    escalate,
+                    approval,
+                }),
+            ..
+        } => (
+            plan::Plan::finish(id, escalate)?,
+            approval.approval(),
+            approval.plan_format,
+        ),
+        Args { run, approval, .. } => (
+            build_plan(&client, run, None).await?,
+            approval.approval(),
+            approval.plan_format,
+        ),
+    };
+    Ok(match plan.approve(approval, format)? {
+        plan::Answer::Yes => {
+            plan.run(&client).await?;
+            ExitCode::SUCCESS
+        }
+        plan::Answer::No if approval == plan::Approval::DryRun => ExitCode::SUCCESS,
+        plan::Answer::No => ExitCode::FAILURE,
+        plan::Answer::NoTerminal => ExitCode::from(NOT_ASKED),
+    })
+}
+
+async fn build_plan(
+    client: &Client,
+    mut args: RunArgs,
+    prepare: Option<plan::Prepare>,
+) -> Result<plan::Plan> {
+    if let Some(id) = args.resume {
+        return plan::Plan::resume(id);
+    }
+    let script = script_path(
+        args.script.as_ref().expect("script is required"),
+        args.rev.is_some(),
+    )?;
+    args.inputs.sort_unstable();
+
+    let config = config::Config::load()?;
+    let build_user = match args.build_user {
+        Some(Some(name)) => Some(user::BuildUser::lookup(&name)?),
+        Some(None) => {
+            let name = config
+                .build_user
+                .as_deref()
+                .with_context(|| format!("no `build_user` configured in {}", config.path))?;
+            Some(user::BuildUser::lookup(name)?)
+        }
+        None => None,
+    };
+    let isolate = args.isolate || args.image.is_some();
+    let options = plan::Options {
+        config,
+        escalate: args
+            .escalate
+            .unwrap_or_else(|| command::Escalate::detect(isolate)),
+        isolate,
+        image: args
+            .image
+            .map(|image| image.map_or(plan::Image::FromTarget, plan::Image::Path)),
+        env: args.env,
+        pass_env: args.pass_env,
+        timeout: args.timeout,
+        shell_on_failure: args.shell_on_failure,
+        reap: !args.no_reap,
+        github_repo: args.repo,
+        remote: args.remote,
+        rev: args.rev,
+        untracked: !args.no_untracked,
+        build_user,
+        fresh_home: match (args.fresh_home, args.keep_home) {
+            (false, _) => None,
+            (true, false) => Some(plan::FreshHome::Remove),
+            (true, true) => Some(plan::FreshHome::Keep),
+        },
+        prepare,
+    };
+    plan::Plan::build(client, &script, &args.inputs, &options).await
+}
+
+// With `--rev`, the job script only needs to exist at that commit, so its path is made absolute
+// (resolving `..` lexically) rather than canonicalized.
+fn script_path(script: &Utf8Path, rev: bool) -> Result<Utf8PathBuf> {
+    if !rev {
+        return script
             .canonicalize_utf8()
-            .context("failed to canonicalize job script path")?,
-        None => bail!("no job script specified\nusage: buildomat-at-home SCRIPT [INPUTS...]"),
+            .context("failed to canonicalize job script path");
+    }
+    let cwd = Utf8PathBuf::try_from(std::env::current_dir()?)?;
+    let mut path = Utf8PathBuf::new();
+    for component in cwd.join(script).components() {
+        match component {
+            Utf8Component::CurDir => {}
+            Utf8Component::ParentDir => {
+                path.pop();
+            }
+            component => path.push(component),
+        }
+    }
+    Ok(path)
+}
+
+fn cache_dir() -> Result<Utf8PathBuf> {
+    xdg_dir("XDG_CACHE_HOME", ".cache")
+}
+
+fn config_dir() -> Result<Utf8PathBuf> {
+    xdg_dir("XDG_CONFIG_HOME", ".config")
+}
+
+fn state_dir() -> Result<Utf8PathBuf> {
+    xdg_dir("XDG_STATE_HOME", ".local/state")
+}
+
+fn xdg_dir(var: &str, default: &str) -> Result<Utf8PathBuf> {
+    let base = match std::env::var_os(var) {
+        Some(dir) => Utf8PathBuf::try_from(std::path::PathBuf::from(dir))?,
+        None => Utf8PathBuf::from(std::env::var("HOME").context("HOME is not set")?).join(default),
     };
+    Ok(base.join("buildomat-at-home"))
+}
 
-    let mut inputs = Vec::new();
-    for arg in args {
-        inputs.push(input::Input::from_str(&arg)?);
+#[cfg(test)]
+#[test]
+fn test_script_path() {
+    let script = Utf8Path::new("/home/user/omicron/./.github/buildomat/jobs/../jobs/gone.sh");
+    assert_eq!(
+        script_path(script, true).unwrap(),
+        "/home/user/omicron/.github/buildomat/jobs/gone.sh"
+    );
+    assert!(script_path(script, false).is_err());
+    let cwd = Utf8PathBuf::try_from(std::env::current_dir().unwrap()).unwrap();
+    assert_eq!(
+        script_path(Utf8Path::new(".github/buildomat/jobs/gone.sh"), true).unwrap(),
+        cwd.join(".github/buildomat/jobs/gone.sh")
+    );
+}
+
+#[cfg(test)]
+#[test]
+fn test_parse_env() {
+    assert_eq!(
+        parse_env("RUST_LOG=debug=1").unwrap(),
+        ("RUST_LOG".to_owned(), "debug=1".to_owned())
+    );
+    assert_eq!(
+        parse_env("EMPTY=").unwrap(),
+        ("EMPTY".to_owned(), String::new())
+    );
+    for bad in ["=value", "NO_VALUE", "MY-VAR=1", "1VAR=1", "A B=1", "ÉTÉ=1"] {
+        assert!(parse_env(bad).is_err(), "{}", bad);
     }
-    inputs.sort_unstable();
+    assert!(parse_env_name("CARGO_HOME").is_ok());
+    assert!(parse_env_name("").is_err());
+    assert!(parse_env_name("PATH=").is_err());
+}
 
-    let plan = plan::Plan::build(&client, &script, &inputs).await?;
-    Ok(if plan.approve()? {
-        plan.run(&client).await?;
-        ExitCode::SUCCESS
-    } else {
-        ExitCode::FAILURE
-    })
+#[cfg(test)]
+#[test]
+fn test_approval() {
+    let approval = |args: &[&str]| {
+        let args = Args::try_parse_from(
+            ["buildomat-at-home"]
+                .iter()
+                .chain(args)
+                .chain(&[".github/buildomat/jobs/build.sh"]),
+        )
+        .unwrap();
+        args.approval.approval()
+    };
+    assert_eq!(approval(&[]), plan::Approval::Prompt);
+    assert_eq!(approval(&["--yes"]), plan::Approval::Yes);
+    assert_eq!(approval(&["-y"]), plan::Approval::Yes);
+    assert_eq!(approval(&["--dry-run"]), plan::Approval::DryRun);
+    assert!(Args::try_parse_from([
+        "buildomat-at-home",
+        "--yes",
+        "--dry-run",
+        ".github/buildomat/jobs/build.sh"
+    ])
+    .is_err());
+    // `plan` only saves the plan.
+    for option in ["--yes", "--dry-run", "--plan-format=json"] {
+        assert!(Args::try_parse_from([
+            "buildomat-at-home",
+            "plan",
+            option,
+            "--out",
+            "plan.json",
+            ".github/buildomat/jobs/build.sh"
+        ])
+        .is_err());
+    }
+    assert!(Args::try_parse_from([
+        "buildomat-at-home",
+        "plan",
+        "--out",
+        "plan.json",
+        ".github/buildomat/jobs/build.sh"
+    ])
+    .is_ok());
 }
@@ -1,27 +1,168 @@
-use crate::command::CommandExt;
+use crate::cache;
+use crate::command::{CommandExt, Escalate};
+use crate::config::Config;
 use crate::input::Input;
-use crate::step::{DownloadArtefact, Step};
-use crate::{JOB_NAME_PROPERTY, OUR_DATASET, POOL};
+use crate::interrupt;
+use crate::isolate::Isolation;
+use crate::journal::{self, Journal, PlanRecord, StepJournal};
+use crate::limits::Limits;
+use crate::log;
+use crate::saved;
+use crate::step::{self, DownloadArtefact, Step};
+use crate::toolchain::{self, Toolchain};
+use crate::user::BuildUser;
+use crate::{
+    is_env_name, ARTEFACT_CACHE_SIZE, ENV_PROPERTY, ISOLATED_ROOT, JOB_NAME_PROPERTY, OUR_DATASET,
+    POOL, PREPARED_PROPERTY,
+};
 use anyhow::{bail, ensure, Context, Result};
 use camino::{Utf8Path, Utf8PathBuf};
 use comrak::{nodes::NodeValue, Arena, ComrakOptions};
-use dialoguer::Confirm;
+use dialoguer::{console::style, Confirm};
+use indicatif::HumanBytes;
 use reqwest::Client;
-use serde::Deserialize;
-use std::collections::{HashMap, HashSet};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::fs::Permissions;
+use std::io::{ErrorKind, IsTerminal};
+use std::os::unix::fs::{DirBuilderExt, MetadataExt, PermissionsExt};
 use std::process::{Command, Output, Stdio};
+use std::time::Duration;
 use ulid::Ulid;
 
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct Plan {
+    // Keys the plan's `StepJournal`
+    pub(crate) id: Ulid,
+    pub(crate) escalate: Escalate,
+    pub(crate) steps: Vec<Step>,
+    pub(crate) shell_on_failure: bool,
+    pub(crate) prepared: Option<Prepared>,
+    // Where copies of the job script go, if it's copied (see `PrivateDir`)
+    #[serde(default)]
+    pub(crate) private_dir: Option<Utf8PathBuf>,
+    // The datasets of the job's inputs, which it needs as they were even if no step touches them
+    #[serde(default)]
+    pub(crate) inputs: Vec<String>,
+    // Where the commit the job runs at came from, if it came from a ref
+    #[serde(default)]
+    pub(crate) source: Option<Source>,
+    // Steps already run, when resuming
+    #[serde(skip)]
+    pub(crate) completed: usize,
+    // Whether this is a stopped plan being resumed, whose next step may have been interrupted
+    #[serde(skip)]
+    pub(crate) resumed: bool,
+}
+
+// A commit and the ref (a branch, a tag or `HEAD`) it was taken from, which it should still be
+// reachable from when the plan runs.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct Source {
+    pub(crate) repo: Utf8PathBuf,
+    pub(crate) commit: String,
+    pub(crate) reference: String,
+}
+
+// What's left to do after `prepare` has set everything up.
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct Prepared {
+    prepare: Prepare,
+    // The `RunScript` step that wasn't run
+    script: Step,
+    id: Ulid,
+    // What's left in place for running the script by hand, for `finish` (or `discard`) to remove:
+    // the private directory, and the fresh home unless it's kept
+    #[serde(default)]
+    pub(crate) leftovers: Vec<Utf8PathBuf>,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub(crate) enum Prepare {
+    Exit,
+    Shell,
+}
+
 #[derive(Debug)]
-pub(crate) struct Plan(pub(crate) Vec<Step>);
+#[allow(clippy::struct_excessive_bools)]
+pub(crate) struct Options {
+    pub(crate) config: Config,
+    pub(crate) escalate: Escalate,
+    pub(crate) isolate: bool,
+    pub(crate) image: Option<Image>,
+    pub(crate) env: Vec<(String, String)>,
+    pub(crate) pass_env: Vec<String>,
+    pub(crate) timeout: Option<Duration>,
+    pub(crate) shell_on_failure: bool,
+    pub(crate) reap: bool,
+    // The GitHub repository the job is for (as `(owner, name)`), instead of finding it from the
+    // URL of `remote`
+    pub(crate) github_repo: Option<(String, String)>,
+    pub(crate) remote: Option<String>,
+    // Clone (and read the job script from) this commit instead of the working tree
+    pub(crate) rev: Option<String>,
+    // Include untracked files in the clone of the repository
+    pub(crate) untracked: bool,
+    pub(crate) build_user: Option<BuildUser>,
+    pub(crate) fresh_home: Option<FreshHome>,
+    // Stop before running the job script
+    pub(crate) prepare: Option<Prepare>,
+}
+
+// What to do with a fresh home directory after the job script succeeds.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum FreshHome {
+    Remove,
+    Keep,
+}
+
+// Whether to run a plan once it's printed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Approval {
+    Prompt,
+    Yes,
+    DryRun,
+}
+
+// How `Plan::approve` prints the plan.
+#[derive(Debug, Clone, Copy, clap::ValueEnum)]
+pub(crate) enum PlanFormat {
+    // Human-readable commands, on stderr
+    Text,
+    // Steps and their commands as JSON, on stdout
+    Json,
+}
+
+#[derive(Debug)]
+pub(crate) enum Image {
+    // The image configured for the job's target
+    FromTarget,
+    Path(Utf8PathBuf),
+}
 
 impl Plan {
     pub(crate) async fn build(
         client: &Client,
         script: &Utf8Path,
         inputs: &[Input],
+        options: &Options,
     ) -> Result<Plan> {
-        let frontmatter = FrontMatter::from_job(script)?;
+        ensure!(
+            !options.isolate || cfg!(target_os = "linux"),
+            "isolated jobs are only supported on Linux"
+        );
+        let id = Ulid::new();
+        ensure!(
+            options.build_user.is_none() || !options.isolate,
+            "a build user can't be used with isolated jobs"
+        );
+        ensure!(
+            options.build_user.is_none()
+                || options.escalate != Escalate::None
+                // SAFETY: `geteuid` is always successful.
+                || unsafe { libc::geteuid() } == 0,
+            "running as a build user needs privileges (see `--escalate`)"
+        );
 
         // Jobs are found in `.github/buildomat/jobs/whatever.sh`; remove that to
         // get the root of the repository.
@@ -40,6 +181,60 @@ impl Plan {
             "script path not within `.github/buildomat/jobs`"
         );
 
+        // With `--rev`, the job script is read from (and the repository cloned at) that commit.
+        let rev = match &options.rev {
+            Some(rev) => Some(Rev::resolve(&repo, rev)?),
+            None => None,
+        };
+        let script_path = script.strip_prefix(&repo)?;
+        let frontmatter = FrontMatter::parse(
+            &read_repo_file(&repo, rev.as_ref(), script_path)?.with_context(|| {
+                format!(
+                    "{} not found in {}",
+                    script_path,
+                    options.rev.as_deref().unwrap_or("the repository")
+                )
+            })?,
+        )?;
+        let target = frontmatter
+            .target
+            .as_ref()
+            .and_then(|target| options.config.targets.get(target));
+        ensure!(
+            cfg!(target_os = "linux")
+                || target.is_none_or(|target| target.memory.is_none() && target.cpus.is_none()),
+            "memory and CPU limits are only supported on Linux"
+        );
+
+        // Jobs running in an image use whatever toolchains it has; otherwise, the job's toolchain
+        // is installed with the `rustup` (and into the home directory) it'll run with.
+        let toolchain = match &frontmatter.rust_toolchain {
+            Some(name) if options.image.is_none() => {
+                let home = match &options.build_user {
+                    Some(user) => user.home.clone(),
+                    None => Utf8PathBuf::from(std::env::var("HOME").context("HOME is not set")?),
+                };
+                let rustup = toolchain::find_rustup(&home).with_context(|| {
+                    format!(
+                        "job needs Rust toolchain `{}`, but rustup isn't installed in {} or on PATH",
+                        name, home
+                    )
+                })?;
+                let toolchain = Toolchain::new(
+                    name,
+                    read_repo_file(&repo, rev.as_ref(), "rust-toolchain.toml".into())?.as_deref(),
+                )?;
+                // We may not be able to run the build user's `rustup` to check, but installing an
+                // installed toolchain is quick.
+                if options.build_user.is_some() || !toolchain.is_installed(&rustup)? {
+                    Some((rustup, toolchain, home))
+                } else {
+                    None
+                }
+            }
+            _ => None,
+        };
+
         let chown = ["-un", "-gn"]
             .into_iter()
             .map(|arg| trim_stdout(&Command::new("id").arg(arg).succeed_output()?))
@@ -48,9 +243,60 @@ impl Plan {
 
         let mut plan = Vec::new();
 
+        // Phase 0: Extract the root filesystem image, if any
+
+        let root = if let Some(image) = &options.image {
+            let image = if let Image::Path(image) = image {
+                image.clone()
+            } else {
+                let target = frontmatter
+                    .target
+                    .as_deref()
+                    .context("job has no `target` to find an image for")?;
+                options
+                    .config
+                    .targets
+                    .get(target)
+                    .and_then(|t| t.image.clone())
+                    .with_context(|| {
+                        format!(
+                            "no image configured for target `{}` in {}",
+                            target, options.config.path
+                        )
+                    })?
+            };
+            let image = image
+                .canonicalize_utf8()
+                .with_context(|| format!("failed to canonicalize image path {}", image))?;
+            if image.is_dir() {
+                Some(image)
+            } else {
+                let dest = cache::image_dir(&image)?;
+                if !dest.exists() {
+                    plan.push(Step::Comment(format!(
+                        "extract image {} to {}",
+                        image, dest
+                    )));
+                    plan.push(Step::ExtractImage {
+                        tarball: image,
+                        dest: dest.clone(),
+                    });
+                }
+                Some(dest)
+            }
+        } else {
+            None
+        };
+
         // Phase 1: Set up rpool/{buildomat-at-home,input,work}
 
+        let journal = Journal::load()?;
+        // Incomplete datasets that an unfinished plan might still be resumed to finish aren't
+        // ours to clean up.
+        let unfinished = journal::unfinished_datasets()?;
+        let mut incomplete = Vec::new();
         let mut mounted: HashMap<String, Utf8PathBuf> = HashMap::new();
+        let mut mountpoints: HashMap<String, Utf8PathBuf> = HashMap::new();
         if dataset_exists(OUR_DATASET)? {
             let output = Command::new("zfs")
                 .args(["list", "-H", "-o", "name,mountpoint", "-r", OUR_DATASET])
@@ -58,7 +304,16 @@ impl Plan {
                 .succeed_output()?;
             for line in trim_stdout(&output)?.lines() {
                 if let Some((dataset, mountpoint)) = line.split_once('\t') {
-                    if mountpoint.starts_with("/input") {
+                    if journal.incomplete().contains(dataset) {
+                        if !unfinished.contains_key(dataset) {
+                            incomplete.push(dataset.to_owned());
+                        }
+                        continue;
+                    }
+                    if mountpoint.starts_with('/') {
+                        mountpoints.insert(dataset.into(), mountpoint.into());
+                    }
+                    if mountpoint.starts_with("/input") && !options.isolate {
                         mounted.insert(dataset.into(), mountpoint.into());
                     }
                 }
@@ -70,35 +325,82 @@ impl Plan {
                 mountpoint: None,
                 create_parents: false,
                 chown: chown.clone(),
+                quota: None,
             });
         }
 
-        let input = format!("{}/input", POOL);
-        if !dataset_exists(&input)? {
-            plan.push(Step::Comment("create rpool/input (at /input)".into()));
+        if !incomplete.is_empty() {
+            plan.push(Step::Comment(
+                "destroy datasets left incomplete by an earlier run".into(),
+            ));
+            for dataset in &incomplete {
+                plan.push(Step::DestroyDataset {
+                    dataset: dataset.clone(),
+                });
+            }
+        }
+
+        let (work, work_mountpoint) = if options.isolate {
+            // Isolated jobs get their own work dataset, mounted outside of /work. The host's /work
+            // and /input only need to exist so that the job's namespace can mount over them (unless
+            // the job runs in an image, where they're created within the image instead).
+            for path in ["/work", "/input"] {
+                if root.is_none() && !Utf8Path::new(path).exists() {
+                    plan.push(Step::Comment(format!(
+                        "create {} for isolated jobs to mount over",
+                        path
+                    )));
+                    plan.push(Step::CreateDirectory { path: path.into() });
+                }
+            }
+
+            let work = format!("{}/work/{}", OUR_DATASET, id);
+            let work_mountpoint = Utf8Path::new(ISOLATED_ROOT)
+                .join("work")
+                .join(id.to_string());
+            plan.push(Step::Comment(format!(
+                "create {} (at {})",
+                work, work_mountpoint
+            )));
             plan.push(Step::CreateDataset {
-                dataset: input,
-                mountpoint: Some("/input".into()),
-                create_parents: false,
+                dataset: work.clone(),
+                mountpoint: Some(work_mountpoint.clone()),
+                create_parents: true,
                 chown: chown.clone(),
+                quota: target.and_then(|target| target.disk),
             });
-        }
+            (work, work_mountpoint)
+        } else {
+            let input = format!("{}/input", POOL);
+            if !dataset_exists(&input)? {
+                plan.push(Step::Comment("create rpool/input (at /input)".into()));
+                plan.push(Step::CreateDataset {
+                    dataset: input,
+                    mountpoint: Some("/input".into()),
+                    create_parents: false,
+                    chown: chown.clone(),
+                    quota: None,
+                });
+            }
 
-        let work = format!("{}/work", POOL);
-        if dataset_exists(&work)? {
-            plan.push(Step::Comment("recreate rpool/work (at /work)".into()));
-            plan.push(Step::DestroyDataset {
+            let work = format!("{}/work", POOL);
+            if dataset_exists(&work)? {
+                plan.push(Step::Comment("recreate rpool/work (at /work)".into()));
+                plan.push(Step::DestroyDataset {
+                    dataset: work.clone(),
+                });
+            } else {
+                plan.push(Step::Comment("create rpool/work (at /work)".into()));
+            }
+            plan.push(Step::CreateDataset {
                 dataset: work.clone(),
+                mountpoint: Some("/work".into()),
+                create_parents: false,
+                chown: chown.clone(),
+                quota: target.and_then(|target| target.disk),
             });
-        } else {
-            plan.push(Step::Comment("create rpool/work (at /work)".into()));
-        }
-        plan.push(Step::CreateDataset {
-            dataset: work.clone(),
-            mountpoint: Some("/work".into()),
-            create_parents: false,
-            chown: chown.clone(),
-        });
+            (work, Utf8PathBuf::from("/work"))
+        };
 
         // Phase 2: Set up input mounts and download artifacts
 
@@ -111,8 +413,11 @@ impl Plan {
         let mut mount_phase = Vec::new();
         let mut readonly_phase = Vec::new();
         let mut downloads = Vec::new();
+        let mut binds = Vec::new();
+        let mut input_datasets = Vec::new();
         for input in inputs {
             let dataset = format!("{}/{}", OUR_DATASET, input);
+            input_datasets.push(dataset.clone());
             let mut check = None;
             let job_name = match input {
                 Input::LocalBuild { .. } => {
@@ -153,11 +458,36 @@ impl Plan {
             if let Input::LocalBuild { .. } = input {
                 mountpoint.push("work");
             }
+            // For isolated jobs, `mountpoint` is where the dataset appears within the job's
+            // namespace, and `host_mountpoint` is where it's actually mounted.
+            let mut host_mountpoint = mountpoint.clone();
+            if options.isolate {
+                host_mountpoint = match mountpoints.get(&dataset) {
+                    Some(mountpoint) => mountpoint.clone(),
+                    None => Utf8Path::new(ISOLATED_ROOT)
+                        .join("input")
+                        .join(input.to_string()),
+                };
+            }
             if let Some(check) = check {
-                let download = if dataset_exists(&dataset)? {
-                    // If `readonly=off`, a previous run was most likely interrupted (since we set
+                let download = if incomplete.contains(&dataset) {
+                    true
+                } else if dataset_exists(&dataset)? {
+                    // Runs from before the journal existed didn't record incomplete datasets. If
+                    // `readonly=off`, one was most likely interrupted (since we set
                     // `readonly=on`) after successfully downloading everything.
                     if dataset_prop(&dataset, "readonly")?.as_deref() == Some("off") {
+                        if let Some(id) = unfinished.get(&dataset) {
+                            bail!(
+                                "input {} is still being set up by plan {}; resume it with \
+                                 `buildomat-at-home --resume={}`, or discard it with \
+                                 `buildomat-at-home discard {}`",
+                                input,
+                                id,
+                                id,
+                                id
+                            );
+                        }
                         mounted.remove(&dataset);
                         cleanup_phase.push(Step::DestroyDataset {
                             dataset: dataset.clone(),
@@ -172,15 +502,16 @@ impl Plan {
                 if download {
                     for (path, url) in check.artefacts() {
                         downloads.push(DownloadArtefact {
-                            path: format!("{}{}", mountpoint, path).into(),
+                            path: format!("{}{}", host_mountpoint, path).into(),
                             url,
                         });
                     }
                     mount_phase.push(Step::CreateDataset {
                         dataset: dataset.clone(),
-                        mountpoint: Some(mountpoint.clone()),
+                        mountpoint: Some(host_mountpoint.clone()),
                         create_parents: true,
                         chown: chown.clone(),
+                        quota: None,
                     });
                     readonly_phase.push(Step::SetDatasetReadOnly {
                         dataset: dataset.clone(),
@@ -188,7 +519,19 @@ impl Plan {
                 }
             }
 
-            if mounted.get(&dataset) == Some(&mountpoint) {
+            if options.isolate {
+                if !mountpoints.contains_key(&dataset)
+                    && !mount_phase.iter().any(|step| {
+                        matches!(step, Step::CreateDataset { dataset: d, .. } if *d == dataset)
+                    })
+                {
+                    mount_phase.push(Step::SetDatasetMountpoint {
+                        dataset: dataset.clone(),
+                        mountpoint: host_mountpoint.clone(),
+                    });
+                }
+                binds.push((host_mountpoint, mountpoint));
+            } else if mounted.get(&dataset) == Some(&mountpoint) {
                 mounted.remove(&dataset);
             } else {
                 mount_phase.push(Step::SetDatasetMountpoint {
@@ -224,6 +567,13 @@ impl Plan {
                 downloads.len()
             )));
             plan.push(Step::DownloadArtefacts(downloads));
+            plan.push(Step::Comment(format!(
+                "prune artefact cache to {}",
+                HumanBytes(ARTEFACT_CACHE_SIZE)
+            )));
+            plan.push(Step::PruneArtefactCache {
+                max_size: ARTEFACT_CACHE_SIZE,
+            });
         }
         if !readonly_phase.is_empty() {
             plan.push(Step::Comment("mark /input datasets read-only".into()));
@@ -232,93 +582,1124 @@ impl Plan {
 
         // Phase 3.1: Clone the repository
 
-        let workdir = if frontmatter.skip_clone {
-            Utf8PathBuf::from("/work")
+        let (head, branch) = if let Some(rev) = &rev {
+            (rev.commit.clone(), rev.branch.clone().unwrap_or_default())
         } else {
-            let mut treeish = trim_stdout(
+            let head = trim_stdout(
                 &Command::new("git")
-                    .args(["stash", "create"])
+                    .args(["rev-parse", "HEAD"])
                     .current_dir(&repo)
                     .succeed_output()?,
             )?;
-            if treeish.is_empty() {
-                treeish = trim_stdout(
-                    &Command::new("git")
-                        .args(["rev-parse", "HEAD"])
-                        .current_dir(&repo)
-                        .succeed_output()?,
-                )?;
-            }
-
-            let remote = trim_stdout(
+            let branch = trim_stdout(
+                &Command::new("git")
+                    .args(["symbolic-ref", "--short", "-q", "HEAD"])
+                    .current_dir(&repo)
+                    .output()?,
+            )?;
+            (head, branch)
+        };
+        let source = match &rev {
+            Some(rev) => rev.reference.clone(),
+            None if branch.is_empty() => Some("HEAD".to_owned()),
+            None => Some(format!("refs/heads/{}", branch)),
+        }
+        .map(|reference| Source {
+            repo: repo.clone(),
+            commit: head.clone(),
+            reference,
+        });
+        let (owner, name) = if let Some(github_repo) = &options.github_repo {
+            github_repo.clone()
+        } else {
+            let remote = options
+                .remote
+                .as_deref()
+                .or(options.config.remote.as_deref())
+                .unwrap_or("origin");
+            let url = trim_stdout(
                 &Command::new("git")
-                    .args(["remote", "get-url", "origin"])
+                    .args(["remote", "get-url", "--end-of-options", remote])
                     .current_dir(&repo)
                     .output()?,
             )?;
-            let mut iter = remote.rsplit(['/', ':']);
-            let dest = if let (Some(mut repo), Some(owner)) = (iter.next(), iter.next()) {
-                repo = repo.strip_suffix(".git").unwrap_or(repo);
-                Utf8Path::new("/work").join(owner).join(repo)
+            ensure!(
+                !url.is_empty(),
+                "repository has no remote `{}`; use --remote to pick another, or --repo OWNER/NAME",
+                remote
+            );
+            parse_github_url(&url).with_context(|| {
+                format!(
+                    "remote `{}` ({}) isn't a GitHub repository; use --repo OWNER/NAME",
+                    remote, url
+                )
+            })?
+        };
+
+        let workdir = if frontmatter.skip_clone {
+            Utf8PathBuf::from("/work")
+        } else {
+            let snapshot = if rev.is_some() {
+                Snapshot {
+                    commit: head.clone(),
+                    changed: Vec::new(),
+                    untracked: Vec::new(),
+                }
             } else {
-                Utf8PathBuf::from("/work")
+                Snapshot::create(&repo, &head, options.untracked)?
             };
+            if !snapshot.untracked.is_empty() {
+                eprintln!(
+                    "{} including {} untracked files in /work (use --no-untracked to leave them out)",
+                    style("==>").yellow(),
+                    snapshot.untracked.len()
+                );
+            }
+
+            let dest = Utf8Path::new("/work").join(&owner).join(&name);
 
-            plan.push(Step::Comment("clone repository into /work".into()));
+            plan.push(Step::Comment(match &options.rev {
+                Some(rev) => format!("clone repository at {} ({}) into /work", rev, head),
+                None => "clone repository into /work".into(),
+            }));
+            if !snapshot.changed.is_empty() {
+                plan.push(Step::Comment(format!(
+                    "including uncommitted changes to {}",
+                    snapshot.changed.join(", ")
+                )));
+            }
+            if !snapshot.untracked.is_empty() {
+                plan.push(Step::Comment(format!(
+                    "including untracked files {}",
+                    snapshot.untracked.join(", ")
+                )));
+            }
             plan.push(Step::CloneRepo {
-                src: repo,
-                treeish,
-                dest: dest.clone(),
+                submodules: local_submodules(&repo, &snapshot.commit)?,
+                lfs: uses_lfs(&repo, &snapshot.commit)?,
+                src: repo.clone(),
+                treeish: snapshot.commit,
+                dest: work_mountpoint.join(dest.strip_prefix("/work")?),
             });
             dest
         };
 
         // Phase 3.2: Run the dang script
 
-        plan.push(Step::Comment("run job script".into()));
-        plan.push(Step::RunScript {
-            script: script.to_owned(),
+        let mut env = buildomat_env(id, &owner, &name, head, branch);
+
+        // Variables from the user, in increasing order of precedence: the repository's config,
+        // `--pass-env`, then `--env`. Variables passed through are only read from our environment
+        // when the script runs, so that their values (often tokens) aren't written anywhere; they
+        // are recorded with the saved input by name only.
+        let repo_config = options.config.repos.get(&format!("{}/{}", owner, name));
+        let mut overrides = BTreeMap::new();
+        let mut pass_env = options.pass_env.iter().cloned().collect::<BTreeSet<_>>();
+        if let Some(repo_config) = repo_config {
+            for key in repo_config.env.keys().chain(&repo_config.pass_env) {
+                ensure!(
+                    is_env_name(key),
+                    "`{}` in {} is not a variable name",
+                    key,
+                    options.config.path
+                );
+            }
+            overrides.extend(repo_config.env.clone());
+            pass_env.extend(repo_config.pass_env.iter().cloned());
+        }
+        for (key, value) in &options.env {
+            pass_env.remove(key);
+            overrides.insert(key.clone(), value.clone());
+        }
+        env.extend(overrides.clone());
+
+        let timeout = options
+            .timeout
+            .or_else(|| target.and_then(|target| target.timeout));
+        let limits = target.map_or_else(Limits::default, |target| Limits {
+            memory: target.memory,
+            cpus: target.cpus,
+            open_files: target.open_files,
+        });
+
+        // The build user probably can't read our checkout, or write to /work before it's handed
+        // over.
+        let mut script = script.to_owned();
+        let private_dir = Utf8PathBuf::from(format!("/tmp/buildomat-at-home-{}", Ulid::new()));
+        let mut uses_private_dir = false;
+        if let Some(rev) = &rev {
+            let dest = private_dir.join("job.sh");
+            plan.push(Step::Comment(format!(
+                "export the job script from {}",
+                options.rev.as_deref().unwrap_or_default()
+            )));
+            plan.push(Step::ExportFile {
+                repo: repo.clone(),
+                object: format!("{}:{}", rev.commit, script_path),
+                dest: dest.clone(),
+            });
+            uses_private_dir = true;
+            script = dest;
+        }
+        if let Some(user) = &options.build_user {
+            // An exported script is already readable.
+            if rev.is_none() {
+                let copy = private_dir.join("job.sh");
+                plan.push(Step::Comment(format!(
+                    "copy the job script for {} to read",
+                    user.name
+                )));
+                plan.push(Step::CopyFile {
+                    src: script,
+                    dest: copy.clone(),
+                });
+                uses_private_dir = true;
+                script = copy;
+            }
+            plan.push(Step::Comment(format!("give /work to {}", user.name)));
+            plan.push(Step::Chown {
+                path: work_mountpoint.clone(),
+                owner: user.owner(),
+            });
+        }
+
+        let home = if options.fresh_home.is_some() {
+            let path = Utf8Path::new(ISOLATED_ROOT)
+                .join("home")
+                .join(id.to_string());
+            let real_home = match &options.build_user {
+                Some(user) => user.home.clone(),
+                None => Utf8PathBuf::from(std::env::var("HOME").context("HOME is not set")?),
+            };
+            let links = options
+                .config
+                .home
+                .link
+                .iter()
+                .filter(|link| real_home.join(link).exists())
+                .map(|link| (link.clone(), real_home.join(link)))
+                .collect();
+            plan.push(Step::Comment(format!(
+                "create a fresh home directory at {}",
+                path
+            )));
+            plan.push(Step::CreateHome {
+                path: path.clone(),
+                owner: chown.clone(),
+                links,
+                netrc_token_env: options.config.home.netrc_token_env.clone(),
+            });
+            if let Some(user) = &options.build_user {
+                plan.push(Step::Chown {
+                    path: path.clone(),
+                    owner: user.owner(),
+                });
+            }
+            Some(path)
+        } else {
+            None
+        };
+
+        if let Some((rustup, toolchain, home)) = toolchain {
+            plan.push(Step::Comment(format!(
+                "install Rust toolchain {}",
+                toolchain.name
+            )));
+            plan.push(Step::InstallToolchain {
+                rustup,
+                toolchain,
+                home,
+                user: options.build_user.clone(),
+            });
+        }
+
+        let run_script = Step::RunScript {
+            script,
             workdir,
             rust_toolchain: frontmatter.rust_toolchain,
-        });
+            env,
+            pass_env: pass_env.clone(),
+            timeout,
+            log: log::path(id)?,
+            limits,
+            reap: options.reap,
+            user: options.build_user.clone(),
+            home: home.clone(),
+            isolation: options.isolate.then(|| Isolation {
+                dataset: work.clone(),
+                work: work_mountpoint,
+                inputs: binds,
+                root,
+                home: home.clone(),
+            }),
+        };
+        let input = Input::LocalBuild { id };
+
+        if let Some(prepare) = options.prepare {
+            // `finish` reads these back to save /work.
+            let mut properties = BTreeMap::from([
+                (PREPARED_PROPERTY.to_owned(), id.to_string()),
+                (JOB_NAME_PROPERTY.to_owned(), frontmatter.name),
+            ]);
+            if !overrides.is_empty() || !pass_env.is_empty() {
+                properties.insert(
+                    ENV_PROPERTY.to_owned(),
+                    step::env_property(&overrides, &pass_env),
+                );
+            }
+            plan.push(Step::Comment(format!(
+                "mark {} as prepared, to be saved as {}",
+                work, input
+            )));
+            plan.push(Step::SetDatasetProperties {
+                dataset: work,
+                properties,
+            });
+            let mut leftovers = Vec::new();
+            if uses_private_dir {
+                leftovers.push(private_dir.clone());
+            }
+            if let (Some(path), Some(FreshHome::Remove)) = (home, options.fresh_home) {
+                leftovers.push(path);
+            }
+            return Ok(Plan {
+                id,
+                escalate: options.escalate,
+                steps: plan,
+                shell_on_failure: false,
+                prepared: Some(Prepared {
+                    prepare,
+                    script: run_script,
+                    id,
+                    leftovers,
+                }),
+                private_dir: uses_private_dir.then_some(private_dir),
+                inputs: input_datasets,
+                source,
+                completed: 0,
+                resumed: false,
+            });
+        }
+
+        plan.push(Step::Comment("run job script".into()));
+        plan.push(run_script);
+        if let (Some(path), Some(FreshHome::Remove)) = (home, options.fresh_home) {
+            plan.push(Step::Comment("remove the fresh home directory".into()));
+            plan.push(Step::RemoveDirectory { path });
+        }
 
         // Phase 4: Clone and promote /work
 
-        let input = Input::LocalBuild { id: Ulid::new() };
-        plan.push(Step::Comment(format!("save /work as {}", input)));
-        plan.push(Step::SaveWorkAsInput {
-            work_dataset: work,
-            new_dataset: format!("{}/{}", OUR_DATASET, input),
-            job_name: frontmatter.name,
+        save_phase(
+            &mut plan,
+            work,
+            options.isolate,
+            frontmatter.name,
+            overrides,
+            pass_env,
             input,
-        });
+        );
 
-        Ok(Plan(plan))
+        Ok(Plan {
+            id,
+            escalate: options.escalate,
+            steps: plan,
+            shell_on_failure: options.shell_on_failure,
+            prepared: None,
+            private_dir: uses_private_dir.then_some(private_dir),
+            inputs: input_datasets,
+            source,
+            completed: 0,
+            resumed: false,
+        })
     }
 
-    pub(crate) fn approve(&self) -> Result<bool> {
-        eprintln!("this will run the following commands:");
-        for step in &self.0 {
-            for command in step.commands_for_approval() {
-                eprintln!("  {}", command);
+    // Builds a plan to save the /work of a job set up by `prepare`.
+    pub(crate) fn finish(id: Option<Ulid>, escalate: Option<Escalate>) -> Result<Plan> {
+        let output = Command::new("zfs")
+            .args(["list", "-H", "-t", "filesystem", "-o"])
+            .arg(format!("name,origin,{}", PREPARED_PROPERTY))
+            .args(["-r", POOL])
+            .succeed_output()?;
+        let (work, id) = find_prepared(std::str::from_utf8(&output.stdout)?, id)?;
+
+        let job_name = dataset_prop(&work, JOB_NAME_PROPERTY)?
+            .with_context(|| format!("failed to get job name of {}", work))?;
+        let (env, pass_env) = match dataset_prop(&work, ENV_PROPERTY)? {
+            Some(env) if env != "-" => step::parse_env_property(&env)?,
+            _ => (BTreeMap::new(), BTreeSet::new()),
+        };
+
+        let mut plan = Vec::new();
+        let isolated = work != format!("{}/work", POOL);
+        save_phase(
+            &mut plan,
+            work,
+            isolated,
+            job_name,
+            env,
+            pass_env,
+            Input::LocalBuild { id },
+        );
+        // The prepared plan's record says what it left in place (see `Prepared`).
+        let leftovers = match PlanRecord::load(id) {
+            Ok(record) => record
+                .plan
+                .prepared
+                .map(|prepared| prepared.leftovers)
+                .unwrap_or_default(),
+            Err(err) => {
+                eprintln!(
+                    "{} not removing anything else `prepare` left in place: {:#}",
+                    style("==>").yellow(),
+                    err
+                );
+                Vec::new()
             }
+        };
+        for path in leftovers {
+            plan.push(Step::Comment(format!(
+                "remove {}, left in place to run the script by hand",
+                path
+            )));
+            plan.push(Step::RemoveDirectory { path });
         }
-        Ok(Confirm::new().with_prompt("continue?").interact()?)
+        Ok(Plan {
+            id: Ulid::new(),
+            escalate: escalate.unwrap_or_else(|| Escalate::detect(isolated)),
+            steps: plan,
+            shell_on_failure: false,
+            prepared: None,
+            private_dir: None,
+            inputs: Vec::new(),
+            source: None,
+            completed: 0,
+            resumed: false,
+        })
+    }
+
+    pub(crate) fn approve(&self, approval: Approval, format: PlanFormat) -> Result<Answer> {
+        match format {
+            PlanFormat::Text => {
+                if self.completed > 0 {
+                    eprintln!(
+                        "resuming plan {}, which completed {} of its {} steps",
+                        self.id,
+                        self.completed,
+                        self.steps.len()
+                    );
+                }
+                eprintln!(
+                    "this will run the following commands (escalating privileges with {}):",
+                    self.escalate
+                );
+                for step in self.rerun().chain(&self.steps[self.completed..]) {
+                    for command in step.commands_for_approval(self.escalate) {
+                        eprintln!("  {}", command);
+                    }
+                    if self.is_retry(step) {
+                        for line in step.describe_retry() {
+                            eprintln!("  {}", line);
+                        }
+                    }
+                }
+            }
+            PlanFormat::Json => println!("{}", serde_json::to_string_pretty(&self.to_json())?),
+        }
+        match approval {
+            Approval::Prompt => ask(
+                "continue?",
+                std::io::stdin().is_terminal(),
+                Some("use --yes to run without asking"),
+            ),
+            Approval::Yes => Ok(Answer::Yes),
+            Approval::DryRun => Ok(Answer::No),
+        }
+    }
+
+    // Each step's kind and parameters, with the commands it runs.
+    pub(crate) fn to_json(&self) -> serde_json::Value {
+        let mut value = serde_json::to_value(self).expect("plans are serializable");
+        for (step, value) in self.steps.iter().zip(
+            value["steps"]
+                .as_array_mut()
+                .expect("steps are serialized as an array"),
+        ) {
+            value["commands"] = step.commands_for_json(self.escalate).into();
+        }
+        value
+    }
+
+    // Loads a plan that failed or was interrupted from its `StepJournal` (the most recent one if
+    // `id` is `None`), to run the steps it didn't complete.
+    pub(crate) fn resume(id: Option<Ulid>) -> Result<Plan> {
+        let record = match id {
+            Some(id) => PlanRecord::load(id)?,
+            None => PlanRecord::load_all()?
+                .into_iter()
+                .rev()
+                .find(|record| !record.is_finished())
+                .context("no unfinished plan to resume")?,
+        };
+        let id = record.plan.id;
+        ensure!(!record.is_finished(), "plan {} already finished", id);
+        // The step it stopped at is run again, so the datasets it uses may have changed.
+        let next = record.plan.steps[record.completed..]
+            .iter()
+            .find(|step| !matches!(step, Step::Comment(_)));
+        if let Some(Step::SaveWorkAsInput { .. }) = next {
+            bail!(
+                "plan {} stopped while saving /work as an input, which can't be resumed; \
+                 discard it with `buildomat-at-home discard {}`",
+                id,
+                id
+            );
+        }
+        // Retrying a clone removes whatever is in its destination, so that must be somewhere
+        // the plan created for it.
+        if let Some(Step::CloneRepo { dest, .. }) = next {
+            ensure!(
+                record.plan.steps.iter().any(|step| matches!(
+                    step,
+                    Step::CreateDataset { mountpoint: Some(mountpoint), .. }
+                        if dest.starts_with(mountpoint)
+                )),
+                "plan {} stopped while cloning into {}, which isn't in a dataset it created, \
+                 so it can't be resumed",
+                id,
+                dest
+            );
+        }
+        let rerun = next.map(Step::datasets).unwrap_or_default();
+        saved::check(
+            record.preconditions.iter().filter(|precondition| {
+                precondition
+                    .dataset()
+                    .is_none_or(|dataset| !rerun.contains(&dataset))
+            }),
+            &format!("plan {} stopped", id),
+        )?;
+        Ok(Plan {
+            completed: record.completed,
+            resumed: true,
+            ..record.plan
+        })
+    }
+
+    // Whether `step` is the one a resumed plan stopped at (see `Step::describe_retry`).
+    fn is_retry(&self, step: &Step) -> bool {
+        self.resumed
+            && self.steps[self.completed..]
+                .iter()
+                .find(|step| !matches!(step, Step::Comment(_)))
+                .is_some_and(|next| std::ptr::eq(next, step))
+    }
+
+    // Steps that were completed before the plan stopped but need running again when it's resumed:
+    // those that copy or export the job script into the private directory, which was removed, and,
+    // if the script is still to run, those that set up a fresh home with a `.netrc`, which was
+    // removed too (see `Netrc`).
+    fn rerun(&self) -> impl Iterator<Item = &Step> {
+        let private_dir = self.private_dir.as_deref();
+        let script_pending = self.prepared.is_some()
+            || self.steps[self.completed..]
+                .iter()
+                .any(|step| matches!(step, Step::RunScript { .. }));
+        let netrc_home = self.netrc_home().filter(|_| script_pending);
+        self.steps[..self.completed]
+            .iter()
+            .filter(move |step| match step {
+                Step::CopyFile { dest, .. } | Step::ExportFile { dest, .. } => {
+                    private_dir.is_some_and(|dir| dest.starts_with(dir))
+                }
+                Step::CreateHome { path, .. } | Step::Chown { path, .. } => {
+                    netrc_home == Some(path)
+                }
+                _ => false,
+            })
+    }
+
+    // The fresh home the plan writes a `.netrc` into, if it does.
+    fn netrc_home(&self) -> Option<&Utf8Path> {
+        self.steps.iter().find_map(|step| match step {
+            Step::CreateHome {
+                path,
+                netrc_token_env: Some(_),
+                ..
+            } => Some(path.as_path()),
+            _ => None,
+        })
     }
 
     pub(crate) async fn run(self, client: &Client) -> Result<()> {
-        for step in self.0 {
-            step.run(client).await?;
+        interrupt::install()?;
+        let mut journal = Journal::load()?;
+        let mut progress = StepJournal::create(&self)?;
+        let private_dir = self
+            .private_dir
+            .as_deref()
+            .map(PrivateDir::create)
+            .transpose()?;
+        let netrc = self.netrc_home().map(|home| Netrc {
+            path: Some(home.join(".netrc")),
+            escalate: self.escalate,
+        });
+        for step in self.rerun() {
+            run_step(step, client, self.escalate, false, &mut journal).await?;
+        }
+
+        if let Some(prepared) = &self.prepared {
+            self.run_steps(
+                &self.steps[self.completed..],
+                client,
+                &mut journal,
+                &mut progress,
+            )
+            .await?;
+            eprintln!(
+                "{} prepared; run `buildomat-at-home finish {}` to save /work as {}",
+                style("==>").blue(),
+                prepared.id,
+                style(Input::LocalBuild { id: prepared.id }).green()
+            );
+            // The script is run by hand from here, so its copy and the `.netrc` are still needed.
+            if let Some(private_dir) = private_dir {
+                private_dir.keep();
+            }
+            if let Some(netrc) = netrc {
+                netrc.keep();
+            }
+            if let Prepare::Shell = prepared.prepare {
+                prepared.script.debug_shell(self.escalate)?;
+            }
+            return Ok(());
+        }
+
+        let mut steps = self.steps[self.completed..].iter();
+        while let Some(step) = steps.next() {
+            let retry = self.is_retry(step);
+            let Err(err) = run_step(step, client, self.escalate, retry, &mut journal).await else {
+                progress.complete(&self)?;
+                continue;
+            };
+            progress.fail(&self, &err)?;
+            if !(self.shell_on_failure && matches!(step, Step::RunScript { .. }))
+                || interrupt::signal().is_some()
+            {
+                return Err(err);
+            }
+
+            eprintln!("{} {:#}", style("==>").red(), err);
+            step.debug_shell(self.escalate)?;
+            // The rest of the plan saves /work as an input (and cleans up after an isolated job).
+            if confirm("save the failed /work as an input?")? {
+                // Resuming from here would carry on saving /work, as the user asked.
+                progress.complete(&self)?;
+                self.run_steps(steps.as_slice(), client, &mut journal, &mut progress)
+                    .await?;
+            }
+            return Err(err);
+        }
+        // Whatever's left of the fresh home (with `--keep-home`) is kept as it is.
+        if let Some(netrc) = netrc {
+            netrc.keep();
         }
         Ok(())
     }
+
+    async fn run_steps(
+        &self,
+        steps: &[Step],
+        client: &Client,
+        journal: &mut Journal,
+        progress: &mut StepJournal,
+    ) -> Result<()> {
+        for step in steps {
+            let retry = self.is_retry(step);
+            if let Err(err) = run_step(step, client, self.escalate, retry, journal).await {
+                progress.fail(self, &err)?;
+                return Err(err);
+            }
+            progress.complete(self)?;
+        }
+        Ok(())
+    }
+}
+
+// A directory for the plan's copies of the job script, named so that it can't be guessed and
+// writable only by us, so that nobody else can put anything in place of a copy (or of the
+// directory). Others can still read a copy by name, as the build user needs to. It's removed when
+// dropped, however the plan stops.
+struct PrivateDir {
+    path: Option<Utf8PathBuf>,
+}
+
+impl PrivateDir {
+    // Creates `path`, or reuses it if it's left from an earlier run of the plan and still ours.
+    fn create(path: &Utf8Path) -> Result<PrivateDir> {
+        match std::fs::DirBuilder::new().mode(0o700).create(path) {
+            Ok(()) => {}
+            Err(err) if err.kind() == ErrorKind::AlreadyExists => {
+                let metadata = std::fs::symlink_metadata(path)?;
+                // SAFETY: `geteuid` has no memory safety requirements.
+                let uid = unsafe { libc::geteuid() };
+                ensure!(
+                    metadata.is_dir() && metadata.uid() == uid && metadata.mode() & 0o022 == 0,
+                    "{} already exists and isn't a directory only we can write to",
+                    path
+                );
+            }
+            Err(err) => return Err(err).with_context(|| format!("failed to create {}", path)),
+        }
+        // Not subject to our umask
+        std::fs::set_permissions(path, Permissions::from_mode(0o711))?;
+        Ok(PrivateDir {
+            path: Some(path.to_owned()),
+        })
+    }
+
+    // Leaves the directory in place.
+    fn keep(mut self) {
+        self.path = None;
+    }
+}
+
+impl Drop for PrivateDir {
+    fn drop(&mut self) {
+        let Some(path) = &self.path else {
+            return;
+        };
+        if let Err(err) = std::fs::remove_dir_all(path) {
+            eprintln!(
+                "{} failed to remove {}: {}",
+                style("==>").yellow(),
+                path,
+                err
+            );
+        }
+    }
+}
+
+// The `.netrc` in a fresh home, which has a token in it. It's removed when dropped unless the plan
+// succeeds, so that it isn't left behind with the home (which is kept for inspection) if the plan
+// fails or is interrupted; a resumed plan writes it again.
+struct Netrc {
+    path: Option<Utf8PathBuf>,
+    escalate: Escalate,
+}
+
+impl Netrc {
+    fn keep(mut self) {
+        self.path = None;
+    }
+}
+
+impl Drop for Netrc {
+    fn drop(&mut self) {
+        let Some(path) = &self.path else {
+            return;
+        };
+        let result = match std::fs::remove_file(path) {
+            Ok(()) => Ok(()),
+            Err(err) if err.kind() == ErrorKind::NotFound => Ok(()),
+            // The home belongs to the build user, if there is one.
+            Err(err) if err.kind() == ErrorKind::PermissionDenied => self
+                .escalate
+                .command("rm")
+                .arg("-f")
+                .arg(path)
+                .succeed()
+                .map_err(|err| format!("{:#}", err)),
+            Err(err) => Err(err.to_string()),
+        };
+        if let Err(err) = result {
+            eprintln!(
+                "{} failed to remove {}: {}",
+                style("==>").yellow(),
+                path,
+                err
+            );
+        }
+    }
+}
+
+// The answer to a yes/no question.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Answer {
+    Yes,
+    No,
+    // There was no terminal to ask on, which counts as no
+    NoTerminal,
+}
+
+// Asks the user a yes/no question, answering no if there's no terminal to ask on.
+// Asks a question that isn't about approving the plan, so `--yes` doesn't answer it.
+fn confirm(prompt: &str) -> Result<bool> {
+    Ok(ask(prompt, std::io::stdin().is_terminal(), None)? == Answer::Yes)
+}
+
+// `hint` says how to answer yes without a terminal, if there's a way.
+fn ask(prompt: &str, is_terminal: bool, hint: Option<&str>) -> Result<Answer> {
+    if !is_terminal {
+        eprintln!(
+            "{} {} no (stdin isn't a terminal{})",
+            style("==>").yellow(),
+            prompt,
+            hint.map(|hint| format!("; {}", hint)).unwrap_or_default()
+        );
+        return Ok(Answer::NoTerminal);
+    }
+    Ok(if Confirm::new().with_prompt(prompt).interact()? {
+        Answer::Yes
+    } else {
+        Answer::No
+    })
+}
+
+async fn run_step(
+    step: &Step,
+    client: &Client,
+    escalate: Escalate,
+    retry: bool,
+    journal: &mut Journal,
+) -> Result<()> {
+    interrupt::check()?;
+    journal.starting(step)?;
+    if let Err(err) = step.run(client, escalate, retry).await {
+        // Commands we ran got the terminal's SIGINT too, and probably failed because of it.
+        return match interrupt::signal() {
+            Some(signal) => Err(err.context(format!("interrupted by {}", interrupt::name(signal)))),
+            None => Err(err),
+        };
+    }
+    journal.finished(step)?;
+    Ok(())
+}
+
+// Finds the work dataset prepared as `id` (or the only one prepared, if `id` is `None`) in
+// `zfs list -o name,origin,PREPARED_PROPERTY` output.
+fn find_prepared(listing: &str, id: Option<Ulid>) -> Result<(String, Ulid)> {
+    let prepared = listing
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split('\t');
+            let (name, origin, prepared) = (fields.next()?, fields.next()?, fields.next()?);
+            // Once saved, the work dataset is a clone of the new input.
+            if origin != "-" {
+                return None;
+            }
+            Some((name.to_owned(), prepared.parse::<Ulid>().ok()?))
+        })
+        .filter(|(_, prepared)| id.is_none_or(|id| id == *prepared))
+        .collect::<Vec<_>>();
+    Ok(match prepared.as_slice() {
+        [prepared] => prepared.clone(),
+        [] => bail!("no prepared job found"),
+        _ => bail!(
+            "more than one job is prepared; specify one of: {}",
+            prepared
+                .iter()
+                .map(|(_, id)| id.to_string())
+                .collect::<Vec<_>>()
+                .join(", ")
+        ),
+    })
+}
+
+// The variables Buildomat sets for jobs it runs for GitHub check suites, as far as we can tell
+// from the local repository. `branch` is empty for a detached HEAD.
+fn buildomat_env(
+    id: Ulid,
+    owner: &str,
+    name: &str,
+    head: String,
+    branch: String,
+) -> BTreeMap<String, String> {
+    let mut env = BTreeMap::new();
+    env.insert("CI".to_owned(), "true".to_owned());
+    env.insert("BUILDOMAT_JOB_ID".to_owned(), id.to_string());
+    env.insert(
+        "GITHUB_REPOSITORY".to_owned(),
+        format!("{}/{}", owner, name),
+    );
+    env.insert("GITHUB_SHA".to_owned(), head);
+    if !branch.is_empty() {
+        env.insert("GITHUB_REF".to_owned(), format!("refs/heads/{}", branch));
+        env.insert("GITHUB_BRANCH".to_owned(), branch);
+    }
+    env
+}
+
+fn save_phase(
+    plan: &mut Vec<Step>,
+    work: String,
+    isolated: bool,
+    job_name: String,
+    env: BTreeMap<String, String>,
+    pass_env: BTreeSet<String>,
+    input: Input,
+) {
+    plan.push(Step::Comment(format!("save /work as {}", input)));
+    plan.push(Step::SaveWorkAsInput {
+        work_dataset: work.clone(),
+        new_dataset: format!("{}/{}", OUR_DATASET, input),
+        job_name,
+        env,
+        pass_env,
+        input,
+    });
+    if isolated {
+        // After the promotion, the isolated work dataset is a clone of the saved input and
+        // can be destroyed.
+        plan.push(Step::Comment(format!("destroy {}", work)));
+        plan.push(Step::DestroyDataset { dataset: work });
+    }
+}
+
+// The commit given with `--rev`, and the ref (and branch) it names, if it does.
+struct Rev {
+    commit: String,
+    reference: Option<String>,
+    branch: Option<String>,
+}
+
+impl Rev {
+    fn resolve(repo: &Utf8Path, rev: &str) -> Result<Rev> {
+        let commit = trim_stdout(
+            &Command::new("git")
+                .args(["rev-parse", "--verify", "--end-of-options"])
+                .arg(format!("{}^{{commit}}", rev))
+                .current_dir(repo)
+                .succeed_output()
+                .with_context(|| format!("`{}` is not a commit", rev))?,
+        )?;
+        let name = trim_stdout(
+            &Command::new("git")
+                .args(["rev-parse", "--verify", "--symbolic-full-name", rev])
+                .current_dir(repo)
+                .output()?,
+        )?;
+        Ok(Rev {
+            commit,
+            branch: name.strip_prefix("refs/heads/").map(ToOwned::to_owned),
+            reference: (!name.is_empty()).then_some(name),
+        })
+    }
+}
+
+// Reads a file from the repository's working tree, or from `rev` if given. Returns `None` if the
+// file doesn't exist.
+fn read_repo_file(repo: &Utf8Path, rev: Option<&Rev>, path: &Utf8Path) -> Result<Option<String>> {
+    if let Some(rev) = rev {
+        let output = Command::new("git")
+            .args(["show", &format!("{}:{}", rev.commit, path)])
+            .current_dir(repo)
+            .output()?;
+        return Ok(if output.status.success() {
+            Some(String::from_utf8(output.stdout)?)
+        } else {
+            None
+        });
+    }
+    match std::fs::read_to_string(repo.join(path)) {
+        Ok(contents) => Ok(Some(contents)),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(err).with_context(|| format!("failed to read {}", path)),
+    }
+}
+
+// A commit of the repository's working tree as it is, to clone into /work.
+struct Snapshot {
+    commit: String,
+    // Files with uncommitted changes (including staged and deleted files)
+    changed: Vec<String>,
+    // Untracked, non-ignored files included in the commit
+    untracked: Vec<String>,
+}
+
+impl Snapshot {
+    // Like `git stash create`, but without touching the real index, and optionally including
+    // untracked files: everything is added to a copy of the index, which is written out as a
+    // commit on top of `head`. If nothing has changed, the commit is `head`.
+    fn create(repo: &Utf8Path, head: &str, untracked: bool) -> Result<Snapshot> {
+        let git = |args: &[&str]| {
+            let mut command = Command::new("git");
+            command.args(args).current_dir(repo);
+            command
+        };
+
+        let status = git(&[
+            "status",
+            "--porcelain",
+            "-z",
+            if untracked {
+                "--untracked-files=all"
+            } else {
+                "--untracked-files=no"
+            },
+        ])
+        .succeed_output()?;
+        let (changed, untracked_paths) = parse_status(&status.stdout)?;
+        let mut snapshot = Snapshot {
+            commit: head.to_owned(),
+            changed,
+            untracked: untracked_paths,
+        };
+        if snapshot.changed.is_empty() && snapshot.untracked.is_empty() {
+            return Ok(snapshot);
+        }
+
+        let tempdir = tempfile::tempdir()?;
+        let index = tempdir.path().join("index");
+        let real_index = repo.join(trim_stdout(
+            &git(&["rev-parse", "--git-path", "index"]).succeed_output()?,
+        )?);
+        if real_index.exists() {
+            std::fs::copy(&real_index, &index)?;
+        }
+        git(&["add", if untracked { "-A" } else { "-u" }])
+            .env("GIT_INDEX_FILE", &index)
+            .succeed()?;
+        let tree = trim_stdout(
+            &git(&["write-tree"])
+                .env("GIT_INDEX_FILE", &index)
+                .succeed_output()?,
+        )?;
+        snapshot.commit = trim_stdout(
+            &git(&[
+                "commit-tree",
+                &tree,
+                "-p",
+                head,
+                "-m",
+                "buildomat-at-home snapshot",
+            ])
+            // Don't depend on the user having an identity configured.
+            .envs(
+                [
+                    "GIT_AUTHOR_NAME",
+                    "GIT_AUTHOR_EMAIL",
+                    "GIT_COMMITTER_NAME",
+                    "GIT_COMMITTER_EMAIL",
+                ]
+                .map(|var| (var, "buildomat-at-home")),
+            )
+            .succeed_output()?,
+        )?;
+        Ok(snapshot)
+    }
+}
+
+// Splits `git status --porcelain -z` output into changed and untracked paths.
+fn parse_status(stdout: &[u8]) -> Result<(Vec<String>, Vec<String>)> {
+    let mut changed = Vec::new();
+    let mut untracked = Vec::new();
+    let mut entries = stdout.split(|&b| b == 0).filter(|e| !e.is_empty());
+    while let Some(entry) = entries.next() {
+        let entry = std::str::from_utf8(entry)?;
+        let (code, path) = entry
+            .split_at_checked(3)
+            .context("unexpected `git status` output")?;
+        if code.starts_with('R') || code.starts_with('C') {
+            // The path it was renamed or copied from follows.
+            entries.next();
+        }
+        if code == "?? " {
+            untracked.push(path.to_owned());
+        } else {
+            changed.push(path.to_owned());
+        }
+    }
+    Ok((changed, untracked))
+}
+
+// If the repository has submodules, finds those checked out locally, which can be cloned without
+// going to the network (and have any commits that haven't been pushed).
+fn local_submodules(
+    repo: &Utf8Path,
+    commit: &str,
+) -> Result<Option<BTreeMap<String, Utf8PathBuf>>> {
+    let gitmodules = format!("{}:.gitmodules", commit);
+    let exists = Command::new("git")
+        .args(["cat-file", "-e", &gitmodules])
+        .current_dir(repo)
+        .output()?
+        .status
+        .success();
+    if !exists {
+        return Ok(None);
+    }
+    let output = Command::new("git")
+        .args([
+            "config",
+            "--blob",
+            &gitmodules,
+            "--get-regexp",
+            r"^submodule\..*\.path$",
+        ])
+        .current_dir(repo)
+        .output()?;
+    let mut submodules = BTreeMap::new();
+    for (name, path) in parse_submodule_paths(std::str::from_utf8(&output.stdout)?)? {
+        let local = repo.join(path);
+        if local.join(".git").exists() {
+            submodules.insert(name.to_owned(), local);
+        }
+    }
+    Ok(Some(submodules))
+}
+
+// Splits `git config --get-regexp '^submodule\..*\.path$'` output into submodule names and paths.
+fn parse_submodule_paths(stdout: &str) -> Result<Vec<(&str, &str)>> {
+    let mut paths = Vec::new();
+    for line in stdout.lines() {
+        if let Some((key, path)) = line.split_once(' ') {
+            let name = key
+                .strip_prefix("submodule.")
+                .and_then(|key| key.strip_suffix(".path"))
+                .context("unexpected `git config` output")?;
+            paths.push((name, path));
+        }
+    }
+    Ok(paths)
+}
+
+// Whether the repository uses Git LFS, as of `commit`.
+fn uses_lfs(repo: &Utf8Path, commit: &str) -> Result<bool> {
+    Ok(Command::new("git")
+        .args([
+            "grep",
+            "-q",
+            "-e",
+            "filter=lfs",
+            commit,
+            "--",
+            ":(glob)**/.gitattributes",
+        ])
+        .current_dir(repo)
+        .output()?
+        .status
+        .success())
+}
+
+// Finds the owner and name of a repository from a GitHub remote URL, in any of the forms GitHub
+// gives out (`https://github.com/owner/name.git`, `git@github.com:owner/name.git`).
+fn parse_github_url(url: &str) -> Option<(String, String)> {
+    let (_, path) = url.split_once("github.com")?;
+    let path = path.strip_prefix([':', '/'])?.trim_end_matches('/');
+    let path = path.strip_suffix(".git").unwrap_or(path);
+    let (owner, name) = path.split_once('/')?;
+    if owner.is_empty() || name.is_empty() || name.contains('/') {
+        return None;
+    }
+    Some((owner.to_owned(), name.to_owned()))
 }
 
 fn trim_stdout(output: &Output) -> Result<String> {
     Ok(std::str::from_utf8(&output.stdout)?.trim().to_owned())
 }
 
-fn dataset_exists(dataset: &str) -> Result<bool> {
+pub(crate) fn dataset_exists(dataset: &str) -> Result<bool> {
     Ok(Command::new("zfs")
         .args(["list", dataset])
         .output()?
@@ -326,7 +1707,7 @@ fn dataset_exists(dataset: &str) -> Result<bool> {
         .success())
 }
 
-fn dataset_prop(dataset: &str, property: &str) -> Result<Option<String>> {
+pub(crate) fn dataset_prop(dataset: &str, property: &str) -> Result<Option<String>> {
     let output = Command::new("zfs")
         .args(["get", "-H", "-o", "value", property, dataset])
         .output()?;
@@ -346,6 +1727,8 @@ struct FrontMatter {
     rust_toolchain: Option<String>,
     #[serde(default)]
     skip_clone: bool,
+    #[serde(default)]
+    target: Option<String>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -354,9 +1737,8 @@ struct Dependency {
 }
 
 impl FrontMatter {
-    fn from_job(path: &Utf8Path) -> Result<FrontMatter> {
-        let file = std::fs::read_to_string(path)?;
-        let frontmatter = file
+    fn parse(script: &str) -> Result<FrontMatter> {
+        let frontmatter = script
             .lines()
             .take_while(|l| l.starts_with('#'))
             .filter(|l| l.starts_with("#:"))
@@ -392,3 +1774,271 @@ impl GitHubCheck {
             .collect()
     }
 }
+
+#[cfg(test)]
+#[test]
+fn test_parse_github_url() {
+    let repo = Some(("oxidecomputer".to_owned(), "omicron".to_owned()));
+    assert_eq!(
+        parse_github_url("https://github.com/oxidecomputer/omicron.git"),
+        repo
+    );
+    assert_eq!(
+        parse_github_url("https://github.com/oxidecomputer/omicron/"),
+        repo
+    );
+    assert_eq!(
+        parse_github_url("git@github.com:oxidecomputer/omicron.git"),
+        repo
+    );
+    assert_eq!(
+        parse_github_url("ssh://git@github.com/oxidecomputer/omicron"),
+        repo
+    );
+    assert_eq!(
+        parse_github_url("https://gitlab.com/oxide/omicron.git"),
+        None
+    );
+    assert_eq!(parse_github_url("/home/me/src/omicron"), None);
+    assert_eq!(parse_github_url("https://github.com/oxidecomputer"), None);
+}
+
+#[cfg(test)]
+#[test]
+fn test_buildomat_env() {
+    let id = "01H3XMET848BWFBC9KFRN1KCWX".parse().unwrap();
+    let sha = "0123456789abcdef0123456789abcdef01234567";
+    let env = buildomat_env(id, "oxidecomputer", "omicron", sha.into(), "main".into());
+    assert_eq!(
+        env,
+        BTreeMap::from(
+            [
+                ("BUILDOMAT_JOB_ID", "01H3XMET848BWFBC9KFRN1KCWX"),
+                ("CI", "true"),
+                ("GITHUB_BRANCH", "main"),
+                ("GITHUB_REF", "refs/heads/main"),
+                ("GITHUB_REPOSITORY", "oxidecomputer/omicron"),
+                ("GITHUB_SHA", sha),
+            ]
+            .map(|(key, value)| (key.to_owned(), value.to_owned()))
+        )
+    );
+    // A detached HEAD has no branch.
+    let env = buildomat_env(id, "oxidecomputer", "omicron", sha.into(), String::new());
+    assert!(!env.contains_key("GITHUB_BRANCH") && !env.contains_key("GITHUB_REF"));
+}
+
+#[cfg(test)]
+#[test]
+fn test_find_prepared() {
+    let a = "01H3XMET848BWFBC9KFRN1KCWX".parse().unwrap();
+    let b = "01H3WX25SMVQ9YEDXDDC832VCV".parse().unwrap();
+    let listing = format!(
+        "rpool\t-\t-\n\
+        rpool/work\t-\t{a}\n\
+        rpool/buildomat-at-home/work/{b}\t-\t{b}\n\
+        rpool/buildomat-at-home/work/{a}\trpool/buildomat-at-home/local/{a}@snapshot\t{a}\n"
+    );
+    assert_eq!(
+        find_prepared(&listing, Some(a)).unwrap(),
+        ("rpool/work".to_owned(), a)
+    );
+    assert_eq!(
+        find_prepared(&listing, Some(b)).unwrap(),
+        (format!("rpool/buildomat-at-home/work/{}", b), b)
+    );
+    assert!(find_prepared(&listing, None)
+        .unwrap_err()
+        .to_string()
+        .starts_with("more than one job is prepared"));
+    assert!(find_prepared("rpool\t-\t-\n", None).is_err());
+}
+
+#[cfg(test)]
+#[test]
+fn test_parse_status() {
+    let stdout = b" M src/main.rs\0R  src/new.rs\0src/old.rs\0A  README.md\0?? notes.txt\0";
+    let (changed, untracked) = parse_status(stdout).unwrap();
+    assert_eq!(changed, ["src/main.rs", "src/new.rs", "README.md"]);
+    assert_eq!(untracked, ["notes.txt"]);
+    assert_eq!(parse_status(b"").unwrap(), (Vec::new(), Vec::new()));
+    assert!(parse_status(b"M\0").is_err());
+}
+
+#[cfg(test)]
+#[test]
+fn test_parse_submodule_paths() {
+    let stdout = "submodule.tools/dtrace.path tools/dtrace\n\
+                  submodule.vendor.lib.path third party/lib\n";
+    assert_eq!(
+        parse_submodule_paths(stdout).unwrap(),
+        [
+            ("tools/dtrace", "tools/dtrace"),
+            ("vendor.lib", "third party/lib")
+        ]
+    );
+    assert!(parse_submodule_paths("").unwrap().is_empty());
+    assert!(parse_submodule_paths("core.bare false\n").is_err());
+}
+
+#[cfg(test)]
+#[test]
+fn test_frontmatter() {
+    let frontmatter = FrontMatter::parse(
+        r#"#!/bin/bash
+#:
+#: name = "helios / build"
+#: variety = "basic"
+#: target = "helios-2.0"
+#: rust_toolchain = "1.70.0"
+#:
+#: [dependencies.package]
+#: job = "helios / package"
+#:
+
+# Not frontmatter: it's after the header.
+#: skip_clone = true
+set -o errexit
+"#,
+    )
+    .unwrap();
+    assert_eq!(frontmatter.name, "helios / build");
+    assert_eq!(frontmatter.target.as_deref(), Some("helios-2.0"));
+    assert_eq!(frontmatter.rust_toolchain.as_deref(), Some("1.70.0"));
+    assert!(!frontmatter.skip_clone);
+    assert_eq!(frontmatter.dependencies["package"].job, "helios / package");
+    assert!(FrontMatter::parse("#!/bin/bash\n#: target = \"helios-2.0\"\n").is_err());
+}
+
+#[cfg(test)]
+#[test]
+fn test_ask_without_terminal() {
+    assert_eq!(
+        ask("continue?", false, Some("use --yes")).unwrap(),
+        Answer::NoTerminal
+    );
+    assert_eq!(ask("save?", false, None).unwrap(), Answer::NoTerminal);
+}
+
+#[cfg(test)]
+#[test]
+fn test_private_dir() {
+    let tempdir = tempfile::tempdir().unwrap();
+    let dir = Utf8Path::from_path(tempdir.path()).unwrap();
+
+    let path = dir.join("private");
+    let private_dir = PrivateDir::create(&path).unwrap();
+    let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+    assert_eq!(mode & 0o777, 0o711);
+    std::fs::write(path.join("job.sh"), "true").unwrap();
+    drop(private_dir);
+    assert!(!path.exists());
+
+    // Left in place for a prepared job, and reused by a later run.
+    PrivateDir::create(&path).unwrap().keep();
+    assert!(path.exists());
+    drop(PrivateDir::create(&path).unwrap());
+    assert!(!path.exists());
+
+    // Anything else there is refused.
+    let shared = dir.join("shared");
+    std::fs::create_dir(&shared).unwrap();
+    std::fs::set_permissions(&shared, Permissions::from_mode(0o777)).unwrap();
+    assert!(PrivateDir::create(&shared).is_err());
+    let link = dir.join("link");
+    std::os::unix::fs::symlink(&shared, &link).unwrap();
+    assert!(PrivateDir::create(&link).is_err());
+    std::fs::write(dir.join("file"), "").unwrap();
+    assert!(PrivateDir::create(&dir.join("file")).is_err());
+}
+
+#[cfg(test)]
+#[test]
+fn test_is_retry() {
+    let mut plan = Plan {
+        id: Ulid::new(),
+        escalate: Escalate::None,
+        steps: vec![
+            Step::Comment("create /work".into()),
+            Step::CreateDataset {
+                dataset: format!("{}/work", POOL),
+                mountpoint: Some("/work".into()),
+                create_parents: false,
+                chown: "user".into(),
+                quota: None,
+            },
+            Step::Comment("clone the repository".into()),
+            Step::RemoveDirectory {
+                path: "/work/oxidecomputer".into(),
+            },
+        ],
+        shell_on_failure: false,
+        prepared: None,
+        private_dir: None,
+        inputs: Vec::new(),
+        source: None,
+        completed: 0,
+        resumed: false,
+    };
+    // Only the step a resumed plan stopped at is retried.
+    assert!(!plan.steps.iter().any(|step| plan.is_retry(step)));
+    plan.resumed = true;
+    assert!(plan.is_retry(&plan.steps[1]));
+    assert!(!plan.is_retry(&plan.steps[0]));
+    plan.completed = 2;
+    assert!(!plan.is_retry(&plan.steps[1]));
+    assert!(plan.is_retry(&plan.steps[3]));
+    assert!(plan.steps[3].describe_retry().is_empty());
+    assert_eq!(plan.steps[1].describe_retry().len(), 1);
+}
+
+#[cfg(test)]
+#[test]
+fn test_netrc() {
+    let tempdir = tempfile::tempdir().unwrap();
+    let home = Utf8Path::from_path(tempdir.path()).unwrap();
+    let netrc = || {
+        std::fs::write(home.join(".netrc"), "password").unwrap();
+        Netrc {
+            path: Some(home.join(".netrc")),
+            escalate: Escalate::None,
+        }
+    };
+    drop(netrc());
+    assert!(!home.join(".netrc").exists());
+    netrc().keep();
+    assert!(home.join(".netrc").exists());
+
+    // A resumed plan sets the home up again, as long as the script hasn't run yet.
+    let mut plan = Plan {
+        id: Ulid::new(),
+        escalate: Escalate::None,
+        steps: vec![
+            Step::CreateHome {
+                path: home.to_owned(),
+                owner: "user".into(),
+                links: Vec::new(),
+                netrc_token_env: Some("GITHUB_TOKEN".into()),
+            },
+            Step::Chown {
+                path: home.to_owned(),
+                owner: "build".into(),
+            },
+            Step::Chown {
+                path: "/work".into(),
+                owner: "build".into(),
+            },
+            step::run_script(None),
+        ],
+        shell_on_failure: false,
+        prepared: None,
+        private_dir: None,
+        inputs: Vec::new(),
+        source: None,
+        completed: 3,
+        resumed: true,
+    };
+    assert_eq!(plan.rerun().count(), 2);
+    plan.completed = 4;
+    assert_eq!(plan.rerun().count(), 0);
+}
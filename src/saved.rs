@@ -0,0 +1,302 @@
+use crate::plan::{self, Plan};
+use crate::step::Step;
+use anyhow::{bail, Context, Result};
+use camino::{Utf8Path, Utf8PathBuf};
+use dialoguer::console::style;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+use std::process::Command;
+
+// A plan saved by `plan --out`, along with the state of the system it was built against. The
+// steps' commands are included for review, but `apply` runs the commands for the steps' `params`.
+#[derive(Debug, Deserialize)]
+struct SavedPlan {
+    #[serde(flatten)]
+    plan: Plan,
+    preconditions: Vec<Precondition>,
+}
+
+// Something a plan assumes about the system, as it was when the plan was built (or last ran a
+// step; see `StepJournal`).
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub(crate) enum Precondition {
+    // `mountpoint` is `None` if the dataset doesn't exist.
+    Dataset {
+        dataset: String,
+        mountpoint: Option<String>,
+    },
+    // A commit (or `commit:path`) to clone or read from a repository.
+    Object {
+        repo: Utf8PathBuf,
+        object: String,
+        exists: bool,
+    },
+    // The commit the job runs at is still on the ref it was taken from (see `plan::Source`).
+    Reachable {
+        repo: Utf8PathBuf,
+        commit: String,
+        reference: String,
+        reachable: bool,
+    },
+}
+
+impl Precondition {
+    pub(crate) fn for_plan(plan: &Plan) -> Result<Vec<Precondition>> {
+        let mut datasets = plan
+            .inputs
+            .iter()
+            .map(String::as_str)
+            .collect::<BTreeSet<_>>();
+        let mut objects = BTreeSet::new();
+        for step in &plan.steps {
+            datasets.extend(step.datasets());
+            match step {
+                Step::CloneRepo { src, treeish, .. } => {
+                    objects.insert((src, treeish));
+                }
+                Step::ExportFile { repo, object, .. } => {
+                    objects.insert((repo, object));
+                }
+                _ => {}
+            }
+        }
+        datasets
+            .into_iter()
+            .map(|dataset| Precondition::Dataset {
+                dataset: dataset.to_owned(),
+                mountpoint: None,
+            })
+            .chain(
+                objects
+                    .into_iter()
+                    .map(|(repo, object)| Precondition::Object {
+                        repo: repo.clone(),
+                        object: object.clone(),
+                        exists: false,
+                    }),
+            )
+            .chain(plan.source.iter().map(|source| Precondition::Reachable {
+                repo: source.repo.clone(),
+                commit: source.commit.clone(),
+                reference: source.reference.clone(),
+                reachable: false,
+            }))
+            .map(|precondition| precondition.observe())
+            .collect()
+    }
+
+    pub(crate) fn dataset(&self) -> Option<&str> {
+        match self {
+            Precondition::Dataset { dataset, .. } => Some(dataset),
+            Precondition::Object { .. } | Precondition::Reachable { .. } => None,
+        }
+    }
+
+    // The same precondition, as the system is now.
+    fn observe(&self) -> Result<Precondition> {
+        Ok(match self {
+            Precondition::Dataset { dataset, .. } => Precondition::Dataset {
+                dataset: dataset.clone(),
+                mountpoint: plan::dataset_prop(dataset, "mountpoint")?,
+            },
+            Precondition::Object { repo, object, .. } => Precondition::Object {
+                repo: repo.clone(),
+                object: object.clone(),
+                exists: Command::new("git")
+                    .args(["cat-file", "-e", object])
+                    .current_dir(repo)
+                    .output()?
+                    .status
+                    .success(),
+            },
+            Precondition::Reachable {
+                repo,
+                commit,
+                reference,
+                ..
+            } => Precondition::Reachable {
+                repo: repo.clone(),
+                commit: commit.clone(),
+                reference: reference.clone(),
+                reachable: Command::new("git")
+                    .args(["merge-base", "--is-ancestor", commit, reference])
+                    .current_dir(repo)
+                    .output()?
+                    .status
+                    .success(),
+            },
+        })
+    }
+
+    fn describe(&self) -> String {
+        match self {
+            Precondition::Dataset {
+                dataset,
+                mountpoint: Some(mountpoint),
+            } => format!("{} exists, mounted at {}", dataset, mountpoint),
+            Precondition::Dataset {
+                dataset,
+                mountpoint: None,
+            } => format!("{} doesn't exist", dataset),
+            Precondition::Object {
+                repo,
+                object,
+                exists: true,
+            } => format!("{} exists in {}", object, repo),
+            Precondition::Object {
+                repo,
+                object,
+                exists: false,
+            } => format!("{} is missing from {}", object, repo),
+            Precondition::Reachable {
+                repo,
+                commit,
+                reference,
+                reachable: true,
+            } => format!("{} is on {} in {}", commit, reference, repo),
+            Precondition::Reachable {
+                repo,
+                commit,
+                reference,
+                reachable: false,
+            } => format!("{} isn't on {} in {}", commit, reference, repo),
+        }
+    }
+}
+
+pub(crate) fn save(plan: &Plan, path: &Utf8Path) -> Result<()> {
+    let mut value = plan.to_json();
+    value["preconditions"] = serde_json::to_value(Precondition::for_plan(plan)?)?;
+    write(path, &value)
+}
+
+// Only we can read a saved plan, which has the job's `--env` values in it (but only the names of
+// the variables passed through from our environment, which `apply` reads again).
+fn write(path: &Utf8Path, value: &serde_json::Value) -> Result<()> {
+    use std::io::Write;
+    use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+
+    let mut file = std::fs::File::options()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(path)
+        .with_context(|| format!("failed to write {}", path))?;
+    // `mode` only applies to a new file.
+    file.set_permissions(std::fs::Permissions::from_mode(0o600))?;
+    file.write_all((serde_json::to_string_pretty(value)? + "\n").as_bytes())
+        .with_context(|| format!("failed to write {}", path))?;
+    Ok(())
+}
+
+// Loads a plan saved by `save`, failing if the system has changed in a way that matters to it.
+pub(crate) fn load(path: &Utf8Path) -> Result<Plan> {
+    let file = std::fs::read_to_string(path).with_context(|| format!("failed to read {}", path))?;
+    let saved: SavedPlan =
+        serde_json::from_str(&file).with_context(|| format!("failed to parse {}", path))?;
+    check(&saved.preconditions, &format!("{} was saved", path))?;
+    Ok(saved.plan)
+}
+
+// Fails if any of `preconditions` no longer hold, showing what changed since `since`.
+pub(crate) fn check<'a>(
+    preconditions: impl IntoIterator<Item = &'a Precondition>,
+    since: &str,
+) -> Result<()> {
+    let mut diff = Vec::new();
+    for expected in preconditions {
+        let found = expected.observe()?;
+        if found != *expected {
+            diff.push(
+                style(format!("- {}", expected.describe()))
+                    .red()
+                    .to_string(),
+            );
+            diff.push(style(format!("+ {}", found.describe())).green().to_string());
+        }
+    }
+    if !diff.is_empty() {
+        bail!(
+            "the system has changed since {}:\n{}",
+            since,
+            diff.join("\n")
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+#[test]
+fn test_save_and_load() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let plan = Plan {
+        id: "01H3XMET848BWFBC9KFRN1KCWX".parse().unwrap(),
+        escalate: crate::command::Escalate::Sudo,
+        steps: crate::step::every_step(),
+        shell_on_failure: true,
+        prepared: None,
+        private_dir: None,
+        inputs: Vec::new(),
+        source: None,
+        completed: 0,
+        resumed: false,
+    };
+    let tempdir = tempfile::tempdir().unwrap();
+    let path = Utf8Path::from_path(tempdir.path())
+        .unwrap()
+        .join("plan.json");
+    // An existing file is made private too.
+    std::fs::write(&path, "").unwrap();
+    let mut value = plan.to_json();
+    value["preconditions"] = serde_json::json!([]);
+    write(&path, &value).unwrap();
+    assert_eq!(
+        std::fs::metadata(&path).unwrap().permissions().mode() & 0o777,
+        0o600
+    );
+
+    let loaded = load(&path).unwrap();
+    assert_eq!(
+        serde_json::to_value(&loaded).unwrap(),
+        serde_json::to_value(&plan).unwrap()
+    );
+    let json = std::fs::read_to_string(&path).unwrap();
+    assert!(json.contains(r#""timeout": "1h 30m""#));
+    assert!(json.contains(r#""input": "local/01H3XMET848BWFBC9KFRN1KCWX""#));
+}
+
+#[cfg(test)]
+#[test]
+fn test_reachable() {
+    let tempdir = tempfile::tempdir().unwrap();
+    let repo = Utf8Path::from_path(tempdir.path()).unwrap();
+    let git = |args: &[&str]| {
+        let output = Command::new("git")
+            .args(["-c", "user.name=test", "-c", "user.email=test@example.com"])
+            .args(args)
+            .current_dir(repo)
+            .output()
+            .unwrap();
+        assert!(output.status.success(), "{:?}", output);
+        String::from_utf8(output.stdout).unwrap().trim().to_owned()
+    };
+    git(&["init", "-q", "-b", "main"]);
+    git(&["commit", "-q", "--allow-empty", "-m", "first"]);
+    let first = git(&["rev-parse", "HEAD"]);
+    git(&["commit", "-q", "--allow-empty", "-m", "second"]);
+    let second = git(&["rev-parse", "HEAD"]);
+
+    let expected = Precondition::Reachable {
+        repo: repo.to_owned(),
+        commit: second,
+        reference: "refs/heads/main".into(),
+        reachable: true,
+    };
+    assert_eq!(expected.observe().unwrap(), expected);
+    // The commit still exists, but the branch has moved away from it.
+    git(&["reset", "-q", "--hard", &first]);
+    assert!(check([&expected], "the test started").is_err());
+}
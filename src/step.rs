@@ -1,21 +1,64 @@
-use crate::{command::CommandExt, input::Input, JOB_NAME_PROPERTY};
-use anyhow::Result;
-use camino::Utf8PathBuf;
+use crate::cache::{self, ArtefactCache};
+use crate::command::{CommandExt, Escalate};
+use crate::interrupt;
+use crate::isolate::Isolation;
+use crate::job;
+use crate::limits::Limits;
+use crate::log::JobLog;
+use crate::plan;
+use crate::toolchain::Toolchain;
+use crate::user::BuildUser;
+use crate::{input::Input, ENV_PROPERTY, JOB_NAME_PROPERTY, OUR_DATASET};
+use anyhow::{Context, Result};
+use camino::{Utf8Path, Utf8PathBuf};
 use dialoguer::console::style;
 use futures_util::stream::{self, StreamExt, TryStreamExt};
-use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
-use reqwest::Client;
+use indicatif::{HumanBytes, MultiProgress, ProgressBar, ProgressStyle};
+use reqwest::header::{ETAG, IF_NONE_MATCH};
+use reqwest::{Client, StatusCode};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::Permissions;
+use std::os::unix::fs::PermissionsExt;
 use std::process::{Command, Stdio};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 use tempfile::NamedTempFile;
 use tokio::io::AsyncWriteExt;
 
-#[derive(Debug)]
+#[derive(Debug, Serialize, Deserialize)]
+#[allow(clippy::large_enum_variant)] // there are only ever a few dozen
+#[serde(tag = "kind", content = "params", rename_all = "snake_case")]
 pub(crate) enum Step {
     Comment(String),
     CloneRepo {
         src: Utf8PathBuf,
         treeish: String,
         dest: Utf8PathBuf,
+        // If the repository has submodules, the local clones to fetch them from instead of their
+        // URLs, by name
+        submodules: Option<BTreeMap<String, Utf8PathBuf>>,
+        // Whether the repository uses Git LFS, in which case LFS objects are fetched from `src`
+        lfs: bool,
+    },
+    Chown {
+        path: Utf8PathBuf,
+        owner: String,
+    },
+    CopyFile {
+        src: Utf8PathBuf,
+        dest: Utf8PathBuf,
+    },
+    CreateDirectory {
+        path: Utf8PathBuf,
+    },
+    CreateHome {
+        path: Utf8PathBuf,
+        owner: String,
+        // (path within the new home, target)
+        links: Vec<(Utf8PathBuf, Utf8PathBuf)>,
+        netrc_token_env: Option<String>,
     },
     CreateDataset {
         dataset: String,
@@ -23,36 +66,84 @@ pub(crate) enum Step {
         mountpoint: Option<Utf8PathBuf>,
         create_parents: bool,
         chown: String,
+        quota: Option<u64>,
     },
     DestroyDataset {
         dataset: String,
     },
     DownloadArtefacts(Vec<DownloadArtefact>),
+    ExportFile {
+        repo: Utf8PathBuf,
+        // `commit:path`
+        object: String,
+        dest: Utf8PathBuf,
+    },
+    ExtractImage {
+        tarball: Utf8PathBuf,
+        dest: Utf8PathBuf,
+    },
     InheritDatasetMountpoint {
         dataset: String,
     },
+    InstallToolchain {
+        rustup: Utf8PathBuf,
+        toolchain: Toolchain,
+        // The home directory the toolchain is installed into
+        home: Utf8PathBuf,
+        user: Option<BuildUser>,
+    },
+    PruneArtefactCache {
+        max_size: u64,
+    },
+    RemoveDirectory {
+        path: Utf8PathBuf,
+    },
     RunScript {
         script: Utf8PathBuf,
         workdir: Utf8PathBuf,
         rust_toolchain: Option<String>,
+        // Set in addition to the variables passed through from our environment
+        env: BTreeMap<String, String>,
+        // Variables read from our environment when the script runs (`--pass-env`), overriding
+        // `env`
+        pass_env: BTreeSet<String>,
+        #[serde(
+            serialize_with = "serialize_duration",
+            deserialize_with = "deserialize_duration"
+        )]
+        timeout: Option<Duration>,
+        log: Utf8PathBuf,
+        limits: Limits,
+        // Terminate processes the script leaves running
+        reap: bool,
+        user: Option<BuildUser>,
+        // Replaces the user's home directory
+        home: Option<Utf8PathBuf>,
+        isolation: Option<Isolation>,
     },
     SaveWorkAsInput {
         work_dataset: String,
         new_dataset: String,
         job_name: String,
+        env: BTreeMap<String, String>,
+        pass_env: BTreeSet<String>,
         input: Input,
     },
     SetDatasetMountpoint {
         dataset: String,
         mountpoint: Utf8PathBuf,
     },
+    SetDatasetProperties {
+        dataset: String,
+        properties: BTreeMap<String, String>,
+    },
     SetDatasetReadOnly {
         dataset: String,
     },
 }
 
 impl Step {
-    fn commands(&self) -> Vec<Command> {
+    fn commands(&self, escalate: Escalate) -> Vec<Command> {
         macro_rules! cmd {
             ($prog:expr, $($arg:expr),*) => {{
                 let mut command = Command::new($prog);
@@ -63,32 +154,140 @@ impl Step {
             }}
         }
 
+        macro_rules! escalated {
+            ($prog:expr, $($arg:expr),*) => {{
+                let mut command = escalate.command($prog);
+                $(
+                    command.arg($arg);
+                )*
+                command
+            }}
+        }
+
         macro_rules! zfs {
             ($($arg:expr),*) => {
-                cmd!["pfexec", "zfs", $($arg),*]
+                escalated!["zfs", $($arg),*]
             };
         }
 
         match self {
-            Step::Comment(_) | Step::DownloadArtefacts(_) => Vec::new(),
-            Step::CloneRepo { src, treeish, dest } => {
-                vec![
-                    cmd!["git", "-C", dest, "init"],
-                    cmd!["git", "-C", dest, "remote", "add", "origin", src],
+            Step::Comment(_) | Step::DownloadArtefacts(_) | Step::PruneArtefactCache { .. } => {
+                Vec::new()
+            }
+            Step::CloneRepo {
+                src,
+                treeish,
+                dest,
+                submodules,
+                lfs,
+            } => {
+                // Cloning from a local path copies the repository's object files as they are,
+                // which is much quicker than fetching (or, with `--reference --dissociate`,
+                // repacking) every object, and leaves /work standing alone: isolated jobs and build
+                // users may not be able to read the local repository, and a saved /work mustn't
+                // depend on it. Object files aren't hard-linked, as /work may be given to the build
+                // user.
+                let mut commands = vec![
+                    cmd![
+                        "git",
+                        "clone",
+                        "--quiet",
+                        "--no-checkout",
+                        "--single-branch",
+                        "--no-hardlinks",
+                        src,
+                        dest
+                    ],
                     cmd!["git", "-C", dest, "fetch", "origin", treeish],
-                    cmd!["git", "-C", dest, "checkout", treeish],
-                ]
+                ];
+                if *lfs {
+                    // LFS files are checked out afterwards, rather than downloaded by the smudge
+                    // filter, once the objects the commit needs (and only those) are fetched from
+                    // the local object store.
+                    commands.push(cmd![
+                        "git",
+                        "-C",
+                        dest,
+                        "-c",
+                        "filter.lfs.smudge=git-lfs smudge --skip -- %f",
+                        "-c",
+                        "filter.lfs.process=git-lfs filter-process --skip",
+                        "checkout",
+                        treeish
+                    ]);
+                    commands.push(cmd![
+                        "git",
+                        "-C",
+                        dest,
+                        "-c",
+                        format!("lfs.url=file://{}", src),
+                        "lfs",
+                        "fetch"
+                    ]);
+                    commands.push(cmd!["git", "-C", dest, "lfs", "checkout"]);
+                } else {
+                    commands.push(cmd!["git", "-C", dest, "checkout", treeish]);
+                }
+                if let Some(submodules) = submodules {
+                    commands.push(cmd!["git", "-C", dest, "submodule", "init"]);
+                    for (name, url) in submodules {
+                        commands.push(cmd![
+                            "git",
+                            "-C",
+                            dest,
+                            "config",
+                            format!("submodule.{}.url", name),
+                            url
+                        ]);
+                    }
+                    // Git refuses to clone submodules from local paths by default.
+                    commands.push(cmd![
+                        "git",
+                        "-C",
+                        dest,
+                        "-c",
+                        "protocol.file.allow=always",
+                        "submodule",
+                        "update",
+                        "--recursive"
+                    ]);
+                }
+                commands
+            }
+            Step::Chown { path, owner } => vec![escalated!["chown", "-R", owner, path]],
+            Step::CopyFile { src, dest } => vec![cmd!["cp", src, dest], cmd!["chmod", "a+r", dest]],
+            Step::CreateDirectory { path } => vec![escalated!["mkdir", "-p", path]],
+            Step::CreateHome {
+                path, owner, links, ..
+            } => {
+                let mut commands = vec![
+                    escalated!["mkdir", "-p", path],
+                    escalated!["chown", owner, path],
+                ];
+                for (link, target) in links {
+                    let link = path.join(link);
+                    if let Some(parent) = link.parent().filter(|parent| parent != path) {
+                        commands.push(cmd!["mkdir", "-p", parent]);
+                    }
+                    // Links are replaced when a resumed plan creates the home again.
+                    commands.push(cmd!["ln", "-sfn", target, link]);
+                }
+                commands
             }
             Step::CreateDataset {
                 dataset,
                 mountpoint,
                 create_parents,
                 chown,
+                quota,
             } => {
                 let mut create_cmd = zfs!["create"];
                 if *create_parents {
                     create_cmd.arg("-p");
                 }
+                if let Some(quota) = quota {
+                    create_cmd.arg("-o").arg(format!("quota={}", quota));
+                }
                 if let Some(mountpoint) = mountpoint {
                     create_cmd
                         .arg("-o")
@@ -98,71 +297,98 @@ impl Step {
                 let mut commands = vec![create_cmd];
 
                 if let Some(mountpoint) = mountpoint {
-                    commands.push(cmd!["pfexec", "chown", chown, mountpoint]);
+                    commands.push(escalated!["chown", chown, mountpoint]);
                 }
 
                 commands
             }
             Step::DestroyDataset { dataset } => vec![zfs!["destroy", dataset]],
+            Step::RemoveDirectory { path } => vec![escalated!["rm", "-rf", path]],
             Step::InheritDatasetMountpoint { dataset } => {
                 vec![zfs!["inherit", "mountpoint", dataset]]
             }
-            Step::RunScript {
-                script,
-                workdir,
-                rust_toolchain,
+            Step::InstallToolchain {
+                rustup,
+                toolchain,
+                home,
+                user,
             } => {
-                let mut command = cmd!["/bin/bash", script];
-                command.current_dir(workdir);
-                command.stdin(Stdio::null());
-
-                command.env_clear();
-                // https://github.com/oxidecomputer/buildomat/blob/4ae0dc9fc1e6e300bba9f959ce264aad2754cdbd/github/server/src/variety/basic.rs#L689
-                // TERM added for nicer output of cargo etc
-                for var in ["HOME", "USER", "LOGNAME", "TERM"] {
-                    if let Some(value) = std::env::var_os(var) {
-                        command.env(var, value);
-                    }
-                }
-
-                let mut path = vec![
-                    "/usr/bin".to_owned(),
-                    "/bin".to_owned(),
-                    "/usr/sbin".to_owned(),
-                    "/sbin".to_owned(),
-                    "/opt/ooce/bin".to_owned(),
-                    "/opt/ooce/sbin".to_owned(),
+                let mut command = cmd![
+                    rustup,
+                    "toolchain",
+                    "install",
+                    &toolchain.name,
+                    "--profile",
+                    "minimal"
                 ];
-
-                if let Some(version) = rust_toolchain {
-                    command.env("RUSTUP_TOOLCHAIN", version);
-                    if let Ok(home) = std::env::var("HOME") {
-                        path.insert(0, format!("{}/.cargo/bin", home));
+                for component in &toolchain.components {
+                    command.args(["--component", component]);
+                }
+                for target in &toolchain.targets {
+                    command.args(["--target", target]);
+                }
+                if let Some(user) = user {
+                    command.env_clear().env("HOME", home);
+                    if let Some(term) = std::env::var_os("TERM") {
+                        command.env("TERM", term);
                     }
+                    command = user.wrap(command, escalate);
                 }
-
-                command.env("PATH", path.join(":"));
+                vec![command]
+            }
+            Step::ExportFile { repo, object, dest } => vec![
+                cmd!["git", "-C", repo, "show", object],
+                cmd!["chmod", "a+r", dest],
+            ],
+            Step::ExtractImage { tarball, dest } => vec![cmd![
+                "tar",
+                "-x",
+                "-f",
+                tarball,
+                "-C",
+                partial_path(dest),
+                // Device nodes can't be created without privileges; isolated jobs get the host's
+                // /dev instead.
+                "--anchored",
+                "--exclude=dev/*",
+                "--exclude=./dev/*"
+            ]],
+            Step::RunScript { user, .. } => {
+                let mut command = self.script_command().unwrap();
+                if let (Some(user), Some(env_file)) = (user, self.env_file()) {
+                    command = user.wrap_job(command, escalate, &env_file);
+                }
+                command.stdin(Stdio::null());
                 vec![command]
             }
             Step::SaveWorkAsInput {
                 work_dataset,
                 new_dataset,
                 job_name,
+                env,
+                pass_env,
                 ..
             } => {
                 let snapshot = format!("{}@snapshot", work_dataset);
+                let mut clone_cmd = zfs![
+                    "clone",
+                    "-p",
+                    "-o",
+                    "readonly=on",
+                    "-o",
+                    format!("{}={}", JOB_NAME_PROPERTY, job_name)
+                ];
+                if !env.is_empty() || !pass_env.is_empty() {
+                    clone_cmd.arg("-o").arg(format!(
+                        "{}={}",
+                        ENV_PROPERTY,
+                        env_property(env, pass_env)
+                    ));
+                }
+                clone_cmd.arg(&snapshot).arg(new_dataset);
                 vec![
                     zfs!["snapshot", &snapshot],
-                    zfs![
-                        "clone",
-                        "-p",
-                        "-o",
-                        "readonly=on",
-                        "-o",
-                        format!("{}={}", JOB_NAME_PROPERTY, job_name),
-                        &snapshot,
-                        &new_dataset
-                    ],
+                    clone_cmd,
                     zfs!["promote", &new_dataset],
                 ]
             }
@@ -170,11 +396,61 @@ impl Step {
                 dataset,
                 mountpoint,
             } => vec![zfs!["set", format!("mountpoint={}", mountpoint), dataset]],
+            Step::SetDatasetProperties {
+                dataset,
+                properties,
+            } => {
+                let mut command = zfs!["set"];
+                for (key, value) in properties {
+                    command.arg(format!("{}={}", key, value));
+                }
+                command.arg(dataset);
+                vec![command]
+            }
             Step::SetDatasetReadOnly { dataset } => vec![zfs!["set", "readonly=on", dataset]],
         }
     }
 
-    pub(crate) fn commands_for_approval(&self) -> Vec<String> {
+    // The commands this step runs, for `--plan-format json`.
+    pub(crate) fn commands_for_json(&self, escalate: Escalate) -> Vec<serde_json::Value> {
+        self.commands(escalate)
+            .iter()
+            .map(|command| {
+                let env = command
+                    .get_envs()
+                    .filter_map(|(key, value)| {
+                        let key = key.to_string_lossy().into_owned();
+                        let value = value?.to_string_lossy().into_owned();
+                        let value = self.redact(&key, value);
+                        Some((key, value))
+                    })
+                    .collect::<BTreeMap<_, _>>();
+                serde_json::json!({
+                    "argv": command.argv(),
+                    "env": env,
+                    "cwd": command.get_current_dir(),
+                })
+            })
+            .collect()
+    }
+
+    // What running this step again differs in when a resumed plan retries it.
+    pub(crate) fn describe_retry(&self) -> Vec<String> {
+        let line = match self {
+            Step::CloneRepo { dest, .. } => format!(
+                "    first removing anything an interrupted clone left in {}",
+                dest
+            ),
+            Step::CreateDataset { dataset, .. } => format!(
+                "    skipped if an interrupted run already created {}",
+                dataset
+            ),
+            _ => return Vec::new(),
+        };
+        vec![style(line).dim().to_string()]
+    }
+
+    pub(crate) fn commands_for_approval(&self, escalate: Escalate) -> Vec<String> {
         match self {
             Step::Comment(comment) => {
                 vec![style(format!("### {}", comment))
@@ -182,18 +458,300 @@ impl Step {
                     .italic()
                     .to_string()]
             }
+            Step::RunScript {
+                timeout,
+                log,
+                limits,
+                isolation,
+                ..
+            } => {
+                let detail = |line: String| style(line).dim().to_string();
+                let mut lines = Vec::new();
+                for command in self.commands(escalate) {
+                    lines.push(command.to_string());
+                }
+                // A build user reads the environment from a file instead.
+                let command = self.script_command().unwrap();
+                if command.get_envs().next().is_some() {
+                    lines.push(detail("    with environment:".into()));
+                }
+                for (key, value) in command.get_envs() {
+                    if let Some(value) = value {
+                        let key = key.to_string_lossy();
+                        let value = self.redact(&key, value.to_string_lossy().into_owned());
+                        let var = format!("{}={}", key, value);
+                        lines.push(detail(format!("      {}", shell_words::quote(&var))));
+                    }
+                }
+                lines.push(detail(format!("    logging output to {}", log)));
+                if let Some(timeout) = timeout {
+                    lines.push(detail(format!(
+                        "    with a timeout of {}",
+                        humantime::format_duration(*timeout)
+                    )));
+                }
+                let limits = limits.describe();
+                if !limits.is_empty() {
+                    lines.push(detail("    with resource limits:".into()));
+                    for line in limits {
+                        lines.push(detail(format!("      {}", line)));
+                    }
+                }
+                if let Some(isolation) = isolation {
+                    lines.push(detail("    in user and mount namespaces with:".into()));
+                    for line in isolation.describe() {
+                        lines.push(detail(format!("      {}", line)));
+                    }
+                }
+                lines
+            }
+            Step::CreateHome {
+                path,
+                netrc_token_env: Some(var),
+                ..
+            } => {
+                let mut lines = self
+                    .commands(escalate)
+                    .into_iter()
+                    .map(|command| command.to_string())
+                    .collect::<Vec<_>>();
+                lines.push(
+                    style(format!(
+                        "    and write {}/.netrc with the GitHub token in ${}",
+                        path, var
+                    ))
+                    .dim()
+                    .to_string(),
+                );
+                lines
+            }
+            Step::ExportFile { dest, .. } => self
+                .commands(escalate)
+                .into_iter()
+                .map(|command| {
+                    if command.get_program() == "git" {
+                        format!(
+                            "{} > {}",
+                            command.to_string(),
+                            shell_words::quote(dest.as_str())
+                        )
+                    } else {
+                        command.to_string()
+                    }
+                })
+                .collect(),
             _ => self
-                .commands()
+                .commands(escalate)
                 .into_iter()
                 .map(|command| command.to_string())
                 .collect(),
         }
     }
 
-    pub(crate) async fn run(&self, client: &Client) -> Result<()> {
+    // Runs an interactive shell with the same working directory, environment and isolation as the
+    // job script. Does nothing for other steps.
+    pub(crate) fn debug_shell(&self, escalate: Escalate) -> Result<()> {
+        let Some(mut command) = self.shell_command() else {
+            return Ok(());
+        };
+        if let Step::RunScript {
+            script,
+            workdir,
+            limits,
+            user,
+            isolation,
+            ..
+        } = self
+        {
+            if let (Some(user), Some(env_file)) = (user, self.env_file()) {
+                let env_file = env_file.with_extension("shell.env");
+                if escalate != Escalate::None {
+                    BuildUser::authenticate(escalate)?;
+                    user.write_env_file(&command, &env_file, escalate)?;
+                }
+                command = user.wrap_job(command, escalate, &env_file);
+            }
+            let _cgroup = limits.apply(&mut command)?;
+            if let Some(user) = user {
+                user.drop_privileges(&mut command, escalate);
+            }
+            if let Some(isolation) = isolation {
+                isolation.apply(&mut command, script, workdir)?;
+            }
+        }
+        eprintln!(
+            "{} starting a shell in the job's environment; exit it to continue",
+            style("==>").blue()
+        );
+        // The shell's exit status is whatever its last command's was, which isn't interesting.
+        interrupt::ignoring(|| command.status())?;
+        Ok(())
+    }
+
+    // Hides the value of a variable passed through from our environment (often a token) when
+    // showing a plan.
+    fn redact(&self, key: &str, value: String) -> String {
+        match self {
+            Step::RunScript { pass_env, .. } if pass_env.contains(key) => {
+                "<from environment>".into()
+            }
+            _ => value,
+        }
+    }
+
+    // The job script `commands` runs, before it's wrapped to run as the build user.
+    fn script_command(&self) -> Option<Command> {
+        let Step::RunScript {
+            script,
+            workdir,
+            isolation,
+            ..
+        } = self
+        else {
+            return None;
+        };
+        let mut command = Command::new("/bin/bash");
+        if let Some(isolation) = isolation {
+            // `Isolation::apply` changes to `workdir` once it's mounted.
+            command.arg(isolation.script_path(script));
+        } else {
+            command.arg(script).current_dir(workdir);
+        }
+        self.job_env(&mut command);
+        Some(command)
+    }
+
+    // The shell `debug_shell` runs, before it's wrapped to run as the build user and limits and
+    // isolation are applied.
+    fn shell_command(&self) -> Option<Command> {
+        let Step::RunScript {
+            workdir, isolation, ..
+        } = self
+        else {
+            return None;
+        };
+        let mut command = Command::new("/bin/bash");
+        command.arg("-i");
+        self.job_env(&mut command);
+        if isolation.is_none() {
+            command.current_dir(workdir);
+        }
+        Some(command)
+    }
+
+    fn job_env(&self, command: &mut Command) {
+        if let Step::RunScript {
+            rust_toolchain,
+            env,
+            pass_env,
+            user,
+            home,
+            ..
+        } = self
+        {
+            script_env(
+                command,
+                rust_toolchain.as_deref(),
+                env,
+                pass_env,
+                user.as_ref(),
+                home.as_deref(),
+            );
+        }
+    }
+
+    // Where a job script run as a build user reads its environment from (see
+    // `BuildUser::wrap_job`), next to the script's log's name.
+    fn env_file(&self) -> Option<Utf8PathBuf> {
+        match self {
+            Step::RunScript { log, .. } => Some(Utf8PathBuf::from(format!(
+                "/tmp/buildomat-at-home-{}.env",
+                log.file_stem().unwrap_or_default()
+            ))),
+            _ => None,
+        }
+    }
+
+    // The datasets this step creates, changes or destroys.
+    pub(crate) fn datasets(&self) -> Vec<&str> {
+        match self {
+            Step::CreateDataset { dataset, .. }
+            | Step::DestroyDataset { dataset }
+            | Step::InheritDatasetMountpoint { dataset }
+            | Step::SetDatasetMountpoint { dataset, .. }
+            | Step::SetDatasetProperties { dataset, .. }
+            | Step::SetDatasetReadOnly { dataset } => vec![dataset],
+            Step::SaveWorkAsInput {
+                work_dataset,
+                new_dataset,
+                ..
+            } => vec![work_dataset, new_dataset],
+            _ => Vec::new(),
+        }
+    }
+
+    // A dataset this step creates that isn't complete until a later step (see `Journal`).
+    pub(crate) fn starts_incomplete(&self) -> Option<&str> {
+        match self {
+            Step::CreateDataset { dataset, .. }
+                if dataset.starts_with(&format!("{}/", OUR_DATASET)) =>
+            {
+                Some(dataset)
+            }
+            _ => None,
+        }
+    }
+
+    // A dataset this step leaves behind for inspection if it fails, which isn't incomplete once
+    // the step starts: an isolated job's work dataset.
+    pub(crate) fn keeps(&self) -> Option<&str> {
+        match self {
+            Step::RunScript {
+                isolation: Some(isolation),
+                ..
+            } => Some(&isolation.dataset),
+            _ => None,
+        }
+    }
+
+    // A dataset this step completes (or removes).
+    pub(crate) fn completes(&self) -> Option<&str> {
+        match self {
+            Step::DestroyDataset { dataset }
+            | Step::SetDatasetProperties { dataset, .. }
+            | Step::SetDatasetReadOnly { dataset } => Some(dataset),
+            _ => None,
+        }
+    }
+
+    // `retry` is set for the step a resumed plan stopped at, which may have been interrupted
+    // partway (see `describe_retry`).
+    pub(crate) async fn run(&self, client: &Client, escalate: Escalate, retry: bool) -> Result<()> {
+        let start = Instant::now();
         if let Step::CloneRepo { dest, .. } = self {
+            if retry && dest.exists() && dest.read_dir()?.next().is_some() {
+                eprintln!(
+                    "{} removing the unfinished clone in {}",
+                    style("==>").blue(),
+                    dest
+                );
+                std::fs::remove_dir_all(dest)?;
+            }
             std::fs::create_dir_all(dest)?;
+        }
+        let existing = match self {
+            Step::CreateDataset { dataset, .. } if retry && plan::dataset_exists(dataset)? => {
+                Some(dataset)
+            }
+            _ => None,
         };
+        if let Step::ExtractImage { dest, .. } = self {
+            let partial = partial_path(dest);
+            if partial.exists() {
+                std::fs::remove_dir_all(&partial)?;
+            }
+            std::fs::create_dir_all(&partial)?;
+        }
         if let Step::DownloadArtefacts(artefacts) = self {
             eprintln!(
                 "{} downloading {} artefacts to /input",
@@ -213,16 +771,138 @@ impl Step {
                 "{bar} {wide_msg} {bytes}/{total_bytes} ({bytes_per_sec})",
             )
             .unwrap();
-            stream::iter(artefacts)
-                .map(|artefact| artefact.download(client, &progress, &progress_meta, style.clone()))
+            let cache = ArtefactCache::open()?;
+            let downloads = stream::iter(artefacts)
+                .map(|artefact| {
+                    artefact.download(client, &cache, &progress, &progress_meta, style.clone())
+                })
                 .buffer_unordered(4)
-                .try_collect::<()>()
-                .await?;
+                .try_collect::<()>();
+            // Dropping the in-progress downloads deletes their temporary files.
+            tokio::select! {
+                result = downloads => result?,
+                () = interrupt::wait() => interrupt::check()?,
+            }
+        }
+        if let Step::PruneArtefactCache { max_size } = self {
+            let cache = ArtefactCache::open()?;
+            let (removed, size) = cache.prune(*max_size)?;
+            eprintln!(
+                "{} artefact cache at {} is {} (removed {} blobs)",
+                style("==>").blue(),
+                cache.dir(),
+                HumanBytes(size),
+                removed
+            );
         }
 
-        for mut command in self.commands() {
+        for (i, mut command) in self.commands(escalate).into_iter().enumerate() {
+            if let (Some(dataset), 0) = (existing, i) {
+                eprintln!(
+                    "{} {} already exists; not creating it",
+                    style("==>").blue(),
+                    dataset
+                );
+                continue;
+            }
             eprintln!("{} {}", style("==>").blue(), command.to_string());
-            command.succeed()?;
+            if let Step::RunScript {
+                script,
+                workdir,
+                timeout,
+                log,
+                limits,
+                reap,
+                user,
+                isolation,
+                ..
+            } = self
+            {
+                // The script runs in its own process group, where a password prompt would stop
+                // it, so any password is asked for first.
+                let env_file = self.env_file().filter(|_| escalate != Escalate::None);
+                if let (Some(user), Some(env_file)) = (user, &env_file) {
+                    BuildUser::authenticate(escalate)?;
+                    user.write_env_file(&self.script_command().unwrap(), env_file, escalate)?;
+                }
+                let cgroup = limits.apply(&mut command)?;
+                if let Some(user) = user {
+                    user.drop_privileges(&mut command, escalate);
+                }
+                if let Some(isolation) = isolation {
+                    isolation.apply(&mut command, script, workdir)?;
+                }
+                let result = job::run(
+                    &mut command,
+                    *timeout,
+                    &Arc::new(JobLog::create(log)?),
+                    cgroup.as_ref(),
+                    *reap,
+                    // Processes running as the build user can only be signalled with privileges.
+                    user.as_ref()
+                        .and(Some(escalate))
+                        .filter(|escalate| *escalate != Escalate::None),
+                )
+                .await;
+                if let (Some(cgroup), Some(memory)) = (&cgroup, limits.memory) {
+                    let kills = cgroup.oom_kills()?;
+                    if kills > 0 {
+                        eprintln!(
+                            "{} {} processes were killed for exceeding the memory limit of {}",
+                            style("==>").red(),
+                            kills,
+                            HumanBytes(memory)
+                        );
+                    }
+                }
+                // The script removes the file once it's read it, unless it failed to start.
+                if let Some(env_file) = env_file.filter(|path| path.exists()) {
+                    if let Err(err) = escalate.command("rm").arg("-f").arg(&env_file).succeed() {
+                        eprintln!(
+                            "{} failed to remove {}: {:#}",
+                            style("==>").yellow(),
+                            env_file,
+                            err
+                        );
+                    }
+                }
+                eprintln!("{} output logged to {}", style("==>").blue(), log);
+                result?;
+            } else {
+                if let Step::InstallToolchain {
+                    user: Some(user), ..
+                } = self
+                {
+                    user.drop_privileges(&mut command, escalate);
+                }
+                if let Step::ExportFile { dest, .. } = self {
+                    if command.get_program() == "git" {
+                        command.stdout(create_export(dest)?);
+                    }
+                }
+                command.succeed()?;
+            }
+        }
+
+        if let Step::CloneRepo { .. } = self {
+            eprintln!(
+                "{} cloned repository in {:.1?}",
+                style("==>").blue(),
+                start.elapsed()
+            );
+        }
+
+        if let Step::ExtractImage { dest, .. } = self {
+            std::fs::rename(partial_path(dest), dest)?;
+        }
+
+        if let Step::CreateHome {
+            path,
+            netrc_token_env: Some(var),
+            ..
+        } = self
+        {
+            write_netrc(path, var)?;
         }
 
         if let Step::SaveWorkAsInput { input, .. } = self {
@@ -237,7 +917,165 @@ impl Step {
     }
 }
 
-#[derive(Debug)]
+// Creates the file an exported job script is written to, in the plan's private directory (see
+// `PrivateDir`), replacing one left by an interrupted run. It's always a new file, so a symlink in
+// its place is never followed.
+fn create_export(dest: &Utf8Path) -> Result<std::fs::File> {
+    use std::os::unix::fs::OpenOptionsExt;
+
+    match std::fs::remove_file(dest) {
+        Ok(()) => {}
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
+        Err(err) => return Err(err).with_context(|| format!("failed to remove {}", dest)),
+    }
+    std::fs::File::options()
+        .write(true)
+        .create_new(true)
+        .mode(0o644)
+        .open(dest)
+        .with_context(|| format!("failed to create {}", dest))
+}
+
+// Writes the `.netrc` Buildomat would write for a job with `access_repos`, using a token from our
+// environment, replacing one left by a plan that was killed before it could remove it (see
+// `plan::Netrc`).
+fn write_netrc(home: &Utf8Path, var: &str) -> Result<()> {
+    use std::io::Write;
+    use std::os::unix::fs::OpenOptionsExt;
+
+    let token = std::env::var(var).with_context(|| format!("failed to read ${}", var))?;
+    let path = home.join(".netrc");
+    match std::fs::remove_file(&path) {
+        Ok(()) => {}
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
+        Err(err) => return Err(err).with_context(|| format!("failed to remove {}", path)),
+    }
+    let mut file = std::fs::File::options()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(&path)?;
+    for machine in ["github.com", "api.github.com"] {
+        writeln!(
+            file,
+            "machine {}\nlogin x-access-token\npassword {}\n",
+            machine, token
+        )?;
+    }
+    Ok(())
+}
+
+#[allow(clippy::ref_option)] // the signature `serialize_with` needs
+fn serialize_duration<S: Serializer>(
+    duration: &Option<Duration>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    duration
+        .map(|duration| humantime::format_duration(duration).to_string())
+        .serialize(serializer)
+}
+
+fn deserialize_duration<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<Duration>, D::Error> {
+    Option::<String>::deserialize(deserializer)?
+        .map(|s| humantime::parse_duration(&s).map_err(serde::de::Error::custom))
+        .transpose()
+}
+
+// Formats variables for `ENV_PROPERTY`: `KEY=VALUE` for those set explicitly, and `KEY` for those
+// passed through from our environment, whose values may be secrets (`zfs get` shows user
+// properties to anyone).
+pub(crate) fn env_property(env: &BTreeMap<String, String>, pass_env: &BTreeSet<String>) -> String {
+    shell_words::join(
+        env.iter()
+            .map(|(key, value)| format!("{}={}", key, value))
+            .chain(pass_env.iter().cloned()),
+    )
+}
+
+// The inverse of `env_property`.
+pub(crate) fn parse_env_property(
+    property: &str,
+) -> Result<(BTreeMap<String, String>, BTreeSet<String>)> {
+    let mut env = BTreeMap::new();
+    let mut pass_env = BTreeSet::new();
+    for var in shell_words::split(property)? {
+        match var.split_once('=') {
+            Some((key, value)) => {
+                env.insert(key.to_owned(), value.to_owned());
+            }
+            None => {
+                pass_env.insert(var);
+            }
+        }
+    }
+    Ok((env, pass_env))
+}
+
+// Sets up the environment the job script runs with.
+fn script_env(
+    command: &mut Command,
+    rust_toolchain: Option<&str>,
+    env: &BTreeMap<String, String>,
+    pass_env: &BTreeSet<String>,
+    user: Option<&BuildUser>,
+    home: Option<&Utf8Path>,
+) {
+    command.env_clear();
+    // https://github.com/oxidecomputer/buildomat/blob/4ae0dc9fc1e6e300bba9f959ce264aad2754cdbd/github/server/src/variety/basic.rs#L689
+    // TERM added for nicer output of cargo etc
+    for var in ["HOME", "USER", "LOGNAME", "TERM"] {
+        if let Some(value) = std::env::var_os(var) {
+            command.env(var, value);
+        }
+    }
+    if let Some(user) = user {
+        command.env("HOME", &user.home);
+        command.env("USER", &user.name);
+        command.env("LOGNAME", &user.name);
+    }
+    if let Some(home) = home {
+        command.env("HOME", home);
+    }
+
+    let mut path = vec![
+        "/usr/bin".to_owned(),
+        "/bin".to_owned(),
+        "/usr/sbin".to_owned(),
+        "/sbin".to_owned(),
+        "/opt/ooce/bin".to_owned(),
+        "/opt/ooce/sbin".to_owned(),
+    ];
+
+    if let Some(version) = rust_toolchain {
+        command.env("RUSTUP_TOOLCHAIN", version);
+        let home = match (home, user) {
+            (Some(home), _) => Some(home.to_string()),
+            (None, Some(user)) => Some(user.home.to_string()),
+            (None, None) => std::env::var("HOME").ok(),
+        };
+        if let Some(home) = home {
+            path.insert(0, format!("{}/.cargo/bin", home));
+        }
+    }
+
+    command.env("PATH", path.join(":"));
+    command.envs(env);
+    for key in pass_env {
+        if let Some(value) = std::env::var_os(key) {
+            command.env(key, value);
+        }
+    }
+}
+
+// Images are extracted here first, so that an interrupted extraction isn't mistaken for a complete
+// one.
+fn partial_path(dest: &Utf8Path) -> Utf8PathBuf {
+    dest.with_extension("partial")
+}
+
+#[derive(Debug, Serialize, Deserialize)]
 pub(crate) struct DownloadArtefact {
     pub(crate) path: Utf8PathBuf,
     pub(crate) url: String,
@@ -247,6 +1085,7 @@ impl DownloadArtefact {
     async fn download(
         &self,
         client: &Client,
+        cache: &ArtefactCache,
         progress: &MultiProgress,
         progress_meta: &ProgressBar,
         style: ProgressStyle,
@@ -256,9 +1095,36 @@ impl DownloadArtefact {
             .parent()
             .expect("download path must have parent directory");
         std::fs::create_dir_all(parent)?;
-        let (file, temp) = NamedTempFile::new_in(parent)?.into_parts();
+        // The server only sends the artefact again if it's changed since it was cached.
+        let cached = cache.lookup(&self.url)?;
+        let mut request = client.get(&self.url);
+        if let Some((etag, _)) = &cached {
+            request = request.header(IF_NONE_MATCH, etag);
+        }
+        let mut response = request.send().await?.error_for_status()?;
+        let etag = response
+            .headers()
+            .get(ETAG)
+            .and_then(|v| v.to_str().ok())
+            .map(ToOwned::to_owned);
+
+        if let Some((cached_etag, blob)) = cached {
+            // Servers that ignore `If-None-Match` still send the same ETag.
+            if response.status() == StatusCode::NOT_MODIFIED || etag == Some(cached_etag) {
+                drop(response);
+                cache::link_or_copy(&blob, &self.path)?;
+                progress.println(format!("{} (cached)", self.path))?;
+                return Ok(());
+            }
+        }
+
+        let (file, temp) = match etag {
+            Some(_) => cache.temp_file()?,
+            None => NamedTempFile::new_in(parent)?,
+        }
+        .into_parts();
         let mut file = tokio::fs::File::from_std(file);
-        let mut response = client.get(&self.url).send().await?.error_for_status()?;
+        let mut hasher = Sha256::new();
         let pbar = progress.insert_from_back(
             1,
             ProgressBar::new(response.content_length().unwrap_or_default())
@@ -267,12 +1133,399 @@ impl DownloadArtefact {
         );
         while let Some(chunk) = response.chunk().await? {
             file.write_all(&chunk).await?;
+            hasher.update(&chunk);
             pbar.inc(chunk.len().try_into().unwrap());
             progress_meta.inc(chunk.len().try_into().unwrap());
         }
         file.flush().await?;
-        temp.persist(&self.path)?;
+        if let Some(etag) = etag {
+            let blob = cache.insert(&self.url, &etag, temp, hasher)?;
+            cache::link_or_copy(&blob, &self.path)?;
+        } else {
+            // The build user reads artefacts too (see `ArtefactCache::insert`).
+            std::fs::set_permissions(&temp, Permissions::from_mode(0o644))?;
+            temp.persist(&self.path)?;
+        }
         pbar.finish();
         Ok(())
     }
 }
+
+#[cfg(test)]
+pub(crate) fn run_script(isolation: Option<Isolation>) -> Step {
+    Step::RunScript {
+        script: "/src/omicron/.github/buildomat/jobs/build.sh".into(),
+        workdir: "/work/oxidecomputer/omicron".into(),
+        rust_toolchain: Some("1.70.0".into()),
+        env: BTreeMap::from([("RUST_LOG".to_owned(), "debug".to_owned())]),
+        pass_env: BTreeSet::new(),
+        timeout: None,
+        log: "/tmp/build.log".into(),
+        limits: Limits::default(),
+        reap: true,
+        user: None,
+        home: None,
+        isolation,
+    }
+}
+
+#[cfg(test)]
+pub(crate) fn isolation() -> Isolation {
+    Isolation {
+        dataset: "rpool/buildomat-at-home/work/01H3XMET848BWFBC9KFRN1KCWX".into(),
+        work: "/var/tmp/buildomat-at-home/work/01H3XMET848BWFBC9KFRN1KCWX".into(),
+        inputs: Vec::new(),
+        root: None,
+        home: None,
+    }
+}
+
+// One of each kind of step, for round-trip tests.
+#[cfg(test)]
+pub(crate) fn every_step() -> Vec<Step> {
+    let id = "01H3XMET848BWFBC9KFRN1KCWX".parse().unwrap();
+    let dataset = || format!("{}/work/01H3XMET848BWFBC9KFRN1KCWX", OUR_DATASET);
+    let user: BuildUser = serde_json::from_value(serde_json::json!({
+        "name": "build",
+        "home": "/home/build",
+        "group": "build",
+        "uid": 1001,
+        "gid": 1001,
+        "groups": [1001],
+    }))
+    .unwrap();
+    let mut run_script = run_script(Some(Isolation {
+        inputs: vec![("/input/build".into(), "/input/build".into())],
+        root: Some("/var/tmp/buildomat-at-home/root".into()),
+        ..isolation()
+    }));
+    if let Step::RunScript {
+        timeout,
+        limits,
+        user: script_user,
+        pass_env,
+        ..
+    } = &mut run_script
+    {
+        *timeout = Some(Duration::from_mins(90));
+        limits.memory = Some(8 << 30);
+        *script_user = Some(user.clone());
+        pass_env.insert("GITHUB_TOKEN".into());
+    }
+    vec![
+        Step::Comment("clone repository into /work".into()),
+        Step::CloneRepo {
+            src: "/home/user/omicron".into(),
+            treeish: "3e9f8f0a4b0e1e0c8cbb5a3d1f3c0ab7e8e2c4d1".into(),
+            dest: "/work/oxidecomputer/omicron".into(),
+            submodules: Some(BTreeMap::new()),
+            lfs: false,
+        },
+        Step::Chown {
+            path: "/work".into(),
+            owner: user.owner(),
+        },
+        Step::CopyFile {
+            src: "/home/user/omicron/.github/buildomat/jobs/build.sh".into(),
+            dest: "/tmp/buildomat-at-home-01H3XMF5Y0N5P3J1V6G6XQ3C3Z/job.sh".into(),
+        },
+        Step::CreateDirectory {
+            path: "/input".into(),
+        },
+        Step::CreateHome {
+            path: "/var/tmp/buildomat-at-home/home/01H3XMET848BWFBC9KFRN1KCWX".into(),
+            owner: "build:build".into(),
+            links: vec![(".rustup".into(), "/home/build/.rustup".into())],
+            netrc_token_env: Some("GITHUB_TOKEN".into()),
+        },
+        Step::CreateDataset {
+            dataset: dataset(),
+            mountpoint: Some("/work".into()),
+            create_parents: true,
+            chown: "user:staff".into(),
+            quota: Some(100 << 30),
+        },
+        Step::DestroyDataset { dataset: dataset() },
+        Step::DownloadArtefacts(vec![DownloadArtefact {
+            path: "/input/build/out.tar.gz".into(),
+            url: "https://example.com/out.tar.gz".into(),
+        }]),
+        Step::ExportFile {
+            repo: "/home/user/omicron".into(),
+            object: "3e9f8f0a4b0e1e0c8cbb5a3d1f3c0ab7e8e2c4d1:.github/buildomat/jobs/build.sh"
+                .into(),
+            dest: "/tmp/buildomat-at-home-01H3XMF5Y0N5P3J1V6G6XQ3C3Z/job.sh".into(),
+        },
+        Step::ExtractImage {
+            tarball: "/home/user/helios.tar.gz".into(),
+            dest: "/var/tmp/buildomat-at-home/root".into(),
+        },
+        Step::InheritDatasetMountpoint { dataset: dataset() },
+        Step::InstallToolchain {
+            rustup: "/home/build/.cargo/bin/rustup".into(),
+            toolchain: Toolchain::new("1.70.0", None).unwrap(),
+            home: "/home/build".into(),
+            user: Some(user),
+        },
+        Step::PruneArtefactCache { max_size: 20 << 30 },
+        Step::RemoveDirectory {
+            path: "/var/tmp/buildomat-at-home/home/01H3XMET848BWFBC9KFRN1KCWX".into(),
+        },
+        run_script,
+        Step::SaveWorkAsInput {
+            work_dataset: dataset(),
+            new_dataset: format!("{}/local/01H3XMET848BWFBC9KFRN1KCWX", OUR_DATASET),
+            job_name: "build".into(),
+            env: BTreeMap::from([("RUST_LOG".to_owned(), "debug".to_owned())]),
+            pass_env: BTreeSet::from(["GITHUB_TOKEN".to_owned()]),
+            input: Input::LocalBuild { id },
+        },
+        Step::SetDatasetMountpoint {
+            dataset: dataset(),
+            mountpoint: "/work".into(),
+        },
+        Step::SetDatasetProperties {
+            dataset: dataset(),
+            properties: BTreeMap::from([(JOB_NAME_PROPERTY.to_owned(), "build".to_owned())]),
+        },
+        Step::SetDatasetReadOnly { dataset: dataset() },
+    ]
+}
+
+#[cfg(test)]
+#[test]
+fn test_run_script_workdir() {
+    let commands = run_script(None).commands(Escalate::None);
+    assert_eq!(
+        commands[0].get_current_dir(),
+        Some(std::path::Path::new("/work/oxidecomputer/omicron"))
+    );
+    // std changes directory before `pre_exec`, when the isolated job's /work isn't mounted yet.
+    let commands = run_script(Some(isolation())).commands(Escalate::None);
+    assert_eq!(commands[0].get_current_dir(), None);
+}
+
+#[cfg(test)]
+#[test]
+fn test_shell_command() {
+    use std::ffi::OsStr;
+
+    let command = run_script(None).shell_command().unwrap();
+    assert_eq!(command.argv(), ["/bin/bash", "-i"]);
+    assert_eq!(
+        command.get_current_dir(),
+        Some(std::path::Path::new("/work/oxidecomputer/omicron"))
+    );
+    let env = command.get_envs().collect::<BTreeMap<_, _>>();
+    assert_eq!(env[OsStr::new("RUST_LOG")], Some(OsStr::new("debug")));
+    assert_eq!(
+        env[OsStr::new("RUSTUP_TOOLCHAIN")],
+        Some(OsStr::new("1.70.0"))
+    );
+
+    let command = run_script(Some(isolation())).shell_command().unwrap();
+    assert_eq!(command.get_current_dir(), None);
+    assert!(Step::Comment("run job script".into())
+        .shell_command()
+        .is_none());
+}
+
+#[cfg(test)]
+#[test]
+fn test_env_property() {
+    let env = BTreeMap::from([
+        ("RUST_LOG".to_owned(), "debug".to_owned()),
+        ("GREETING".to_owned(), "hello world".to_owned()),
+        ("EMPTY".to_owned(), String::new()),
+    ]);
+    let pass_env = BTreeSet::from(["GITHUB_TOKEN".to_owned()]);
+    let property = env_property(&env, &pass_env);
+    assert_eq!(
+        property,
+        "'EMPTY=' 'GREETING=hello world' 'RUST_LOG=debug' GITHUB_TOKEN"
+    );
+    assert_eq!(parse_env_property(&property).unwrap(), (env, pass_env));
+}
+
+#[cfg(test)]
+#[test]
+fn test_create_home() {
+    let step = Step::CreateHome {
+        path: "/var/tmp/buildomat-at-home/home/01H3XMET848BWFBC9KFRN1KCWX".into(),
+        owner: "build:build".into(),
+        links: vec![
+            (".rustup".into(), "/home/build/.rustup".into()),
+            (".cargo/bin".into(), "/home/build/.cargo/bin".into()),
+        ],
+        netrc_token_env: Some("GITHUB_TOKEN".into()),
+    };
+    let commands = step
+        .commands(Escalate::Sudo)
+        .iter()
+        .map(CommandExt::to_string)
+        .collect::<Vec<_>>();
+    assert_eq!(
+        commands,
+        [
+            "sudo mkdir -p /var/tmp/buildomat-at-home/home/01H3XMET848BWFBC9KFRN1KCWX",
+            "sudo chown build:build /var/tmp/buildomat-at-home/home/01H3XMET848BWFBC9KFRN1KCWX",
+            "ln -sfn /home/build/.rustup \
+             /var/tmp/buildomat-at-home/home/01H3XMET848BWFBC9KFRN1KCWX/.rustup",
+            "mkdir -p /var/tmp/buildomat-at-home/home/01H3XMET848BWFBC9KFRN1KCWX/.cargo",
+            "ln -sfn /home/build/.cargo/bin \
+             /var/tmp/buildomat-at-home/home/01H3XMET848BWFBC9KFRN1KCWX/.cargo/bin",
+        ]
+    );
+}
+
+#[cfg(test)]
+#[test]
+fn test_write_netrc() {
+    let tempdir = tempfile::tempdir().unwrap();
+    let home = Utf8Path::from_path(tempdir.path()).unwrap();
+    std::env::set_var("BUILDOMAT_AT_HOME_TEST_NETRC_TOKEN", "ghp_secret");
+    write_netrc(home, "BUILDOMAT_AT_HOME_TEST_NETRC_TOKEN").unwrap();
+    let netrc = home.join(".netrc");
+    assert_eq!(
+        std::fs::read_to_string(&netrc).unwrap(),
+        "machine github.com\nlogin x-access-token\npassword ghp_secret\n\n\
+         machine api.github.com\nlogin x-access-token\npassword ghp_secret\n\n"
+    );
+    assert_eq!(
+        std::fs::metadata(&netrc).unwrap().permissions().mode() & 0o777,
+        0o600
+    );
+    assert!(write_netrc(home, "BUILDOMAT_AT_HOME_TEST_NETRC_MISSING").is_err());
+}
+
+#[cfg(test)]
+#[test]
+fn test_install_toolchain() {
+    let step = Step::InstallToolchain {
+        rustup: "/home/build/.cargo/bin/rustup".into(),
+        toolchain: Toolchain {
+            name: "1.70.0".into(),
+            components: vec!["clippy".into()],
+            targets: vec!["x86_64-unknown-illumos".into()],
+        },
+        home: "/home/build".into(),
+        user: None,
+    };
+    let commands = step.commands(Escalate::None);
+    assert_eq!(
+        commands[0].argv(),
+        [
+            "/home/build/.cargo/bin/rustup",
+            "toolchain",
+            "install",
+            "1.70.0",
+            "--profile",
+            "minimal",
+            "--component",
+            "clippy",
+            "--target",
+            "x86_64-unknown-illumos",
+        ]
+    );
+}
+
+#[cfg(test)]
+#[test]
+fn test_clone_repo() {
+    let step = Step::CloneRepo {
+        src: "/home/user/omicron".into(),
+        treeish: "3e9f8f0a4b0e1e0c8cbb5a3d1f3c0ab7e8e2c4d1".into(),
+        dest: "/work/oxidecomputer/omicron".into(),
+        submodules: Some(BTreeMap::from([(
+            "tools/dtrace".to_owned(),
+            "/home/user/omicron/tools/dtrace".into(),
+        )])),
+        lfs: true,
+    };
+    let commands = step
+        .commands(Escalate::None)
+        .iter()
+        .map(CommandExt::to_string)
+        .collect::<Vec<_>>();
+    assert_eq!(
+        commands,
+        [
+            "git clone --quiet --no-checkout --single-branch --no-hardlinks /home/user/omicron \
+             /work/oxidecomputer/omicron",
+            "git -C /work/oxidecomputer/omicron fetch origin \
+             3e9f8f0a4b0e1e0c8cbb5a3d1f3c0ab7e8e2c4d1",
+            "git -C /work/oxidecomputer/omicron -c 'filter.lfs.smudge=git-lfs smudge --skip -- %f' \
+             -c 'filter.lfs.process=git-lfs filter-process --skip' checkout \
+             3e9f8f0a4b0e1e0c8cbb5a3d1f3c0ab7e8e2c4d1",
+            "git -C /work/oxidecomputer/omicron -c 'lfs.url=file:///home/user/omicron' lfs fetch",
+            "git -C /work/oxidecomputer/omicron lfs checkout",
+            "git -C /work/oxidecomputer/omicron submodule init",
+            "git -C /work/oxidecomputer/omicron config submodule.tools/dtrace.url \
+             /home/user/omicron/tools/dtrace",
+            "git -C /work/oxidecomputer/omicron -c 'protocol.file.allow=always' submodule update \
+             --recursive",
+        ]
+    );
+}
+
+#[cfg(test)]
+#[test]
+fn test_export_file() {
+    let step = Step::ExportFile {
+        repo: "/home/user/omicron".into(),
+        object: "3e9f8f0a4b0e1e0c8cbb5a3d1f3c0ab7e8e2c4d1:.github/buildomat/jobs/build.sh".into(),
+        dest: "/tmp/buildomat-at-home-01H3XMF5Y0N5P3J1V6G6XQ3C3Z/job.sh".into(),
+    };
+    assert_eq!(
+        step.commands_for_approval(Escalate::None),
+        [
+            "git -C /home/user/omicron show \
+             3e9f8f0a4b0e1e0c8cbb5a3d1f3c0ab7e8e2c4d1:.github/buildomat/jobs/build.sh \
+             > /tmp/buildomat-at-home-01H3XMF5Y0N5P3J1V6G6XQ3C3Z/job.sh",
+            // The build user must be able to read it whatever our umask.
+            "chmod a+r /tmp/buildomat-at-home-01H3XMF5Y0N5P3J1V6G6XQ3C3Z/job.sh",
+        ]
+    );
+}
+
+#[cfg(test)]
+#[test]
+fn test_create_export() {
+    use std::io::Write;
+
+    let tempdir = tempfile::tempdir().unwrap();
+    let dir = Utf8Path::from_path(tempdir.path()).unwrap();
+    let dest = dir.join("job.sh");
+    write!(create_export(&dest).unwrap(), "old").unwrap();
+    write!(create_export(&dest).unwrap(), "new").unwrap();
+    assert_eq!(std::fs::read_to_string(&dest).unwrap(), "new");
+
+    let target = dir.join("target");
+    std::fs::write(&target, "untouched").unwrap();
+    std::fs::remove_file(&dest).unwrap();
+    std::os::unix::fs::symlink(&target, &dest).unwrap();
+    write!(create_export(&dest).unwrap(), "new").unwrap();
+    assert_eq!(std::fs::read_to_string(&target).unwrap(), "untouched");
+    assert_eq!(std::fs::read_to_string(&dest).unwrap(), "new");
+}
+
+#[cfg(test)]
+#[test]
+fn test_redact_pass_env() {
+    std::env::set_var("BUILDOMAT_AT_HOME_TEST_TOKEN", "ghp_secret");
+    let mut step = run_script(None);
+    if let Step::RunScript { pass_env, .. } = &mut step {
+        pass_env.insert("BUILDOMAT_AT_HOME_TEST_TOKEN".into());
+    }
+    let commands = step.commands_for_json(Escalate::None);
+    assert_eq!(
+        commands[0]["env"]["BUILDOMAT_AT_HOME_TEST_TOKEN"],
+        "<from environment>"
+    );
+    assert_eq!(commands[0]["env"]["RUST_LOG"], "debug");
+    let lines = step.commands_for_approval(Escalate::None);
+    assert!(lines
+        .iter()
+        .any(|line| line.contains("BUILDOMAT_AT_HOME_TEST_TOKEN=<from environment>")));
+    assert!(!lines.iter().any(|line| line.contains("ghp_secret")));
+}
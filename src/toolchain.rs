@@ -0,0 +1,136 @@
+use crate::command::CommandExt;
+use anyhow::{Context, Result};
+use camino::{Utf8Path, Utf8PathBuf};
+use serde::{Deserialize, Serialize};
+use std::process::Command;
+
+// The `rust_toolchain` a job asks for, with the components and targets listed in the repository's
+// `rust-toolchain.toml` (if any).
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct Toolchain {
+    pub(crate) name: String,
+    pub(crate) components: Vec<String>,
+    pub(crate) targets: Vec<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct ToolchainFile {
+    #[serde(default)]
+    toolchain: ToolchainSection,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct ToolchainSection {
+    #[serde(default)]
+    components: Vec<String>,
+    #[serde(default)]
+    targets: Vec<String>,
+}
+
+impl Toolchain {
+    // `file` is the repository's `rust-toolchain.toml`, if it has one.
+    pub(crate) fn new(name: &str, file: Option<&str>) -> Result<Toolchain> {
+        let file: ToolchainFile = match file {
+            Some(file) => toml::from_str(file).context("failed to parse rust-toolchain.toml")?,
+            None => ToolchainFile::default(),
+        };
+        Ok(Toolchain {
+            name: name.to_owned(),
+            components: file.toolchain.components,
+            targets: file.toolchain.targets,
+        })
+    }
+
+    // Whether the toolchain and all of its components and targets are installed.
+    pub(crate) fn is_installed(&self, rustup: &Utf8Path) -> Result<bool> {
+        let list = |args: &[&str]| -> Result<Vec<String>> {
+            let output = Command::new(rustup)
+                .args(args)
+                .env_remove("RUSTUP_TOOLCHAIN")
+                .succeed_output()?;
+            Ok(std::str::from_utf8(&output.stdout)?
+                .lines()
+                .filter_map(|line| line.split_whitespace().next())
+                .map(ToOwned::to_owned)
+                .collect())
+        };
+        // Toolchains and most components are listed with the host triple appended.
+        let matches = |installed: &str, wanted: &str| {
+            installed == wanted
+                || installed
+                    .strip_prefix(wanted)
+                    .is_some_and(|rest| rest.starts_with('-'))
+        };
+
+        let toolchains = list(&["toolchain", "list"])?;
+        if !toolchains.iter().any(|t| matches(t, &self.name)) {
+            return Ok(false);
+        }
+        let components = list(&[
+            "component",
+            "list",
+            "--installed",
+            "--toolchain",
+            &self.name,
+        ])?;
+        let targets = list(&["target", "list", "--installed", "--toolchain", &self.name])?;
+        Ok(self
+            .components
+            .iter()
+            .all(|wanted| components.iter().any(|c| matches(c, wanted)))
+            && self.targets.iter().all(|wanted| targets.contains(wanted)))
+    }
+}
+
+// Finds the `rustup` the job script would use: the one in `~/.cargo/bin`, which is at the front
+// of the script's `PATH`, or else one on our `PATH`.
+pub(crate) fn find_rustup(home: &Utf8Path) -> Option<Utf8PathBuf> {
+    let rustup = home.join(".cargo").join("bin").join("rustup");
+    if rustup.is_file() {
+        return Some(rustup);
+    }
+    let path = std::env::var_os("PATH").unwrap_or_default();
+    std::env::split_paths(&path)
+        .filter_map(|dir| Utf8PathBuf::try_from(dir).ok())
+        .map(|dir| dir.join("rustup"))
+        .find(|rustup| rustup.is_file())
+}
+
+#[cfg(test)]
+#[test]
+fn test_toolchain_new() {
+    let toolchain = Toolchain::new(
+        "1.70.0",
+        Some(
+            r#"
+[toolchain]
+channel = "1.70.0"
+profile = "default"
+components = ["clippy", "rustfmt"]
+targets = ["x86_64-unknown-illumos"]
+"#,
+        ),
+    )
+    .unwrap();
+    assert_eq!(toolchain.name, "1.70.0");
+    assert_eq!(toolchain.components, ["clippy", "rustfmt"]);
+    assert_eq!(toolchain.targets, ["x86_64-unknown-illumos"]);
+
+    let toolchain = Toolchain::new("nightly-2023-06-01", None).unwrap();
+    assert!(toolchain.components.is_empty() && toolchain.targets.is_empty());
+    // A file with only a channel is fine too.
+    let toolchain = Toolchain::new("stable", Some("[toolchain]\nchannel = \"stable\"\n")).unwrap();
+    assert!(toolchain.components.is_empty());
+    assert!(Toolchain::new("stable", Some("[toolchain")).is_err());
+}
+
+#[cfg(test)]
+#[test]
+fn test_find_rustup() {
+    let tempdir = tempfile::tempdir().unwrap();
+    let home = Utf8Path::from_path(tempdir.path()).unwrap();
+    let bin = home.join(".cargo").join("bin");
+    std::fs::create_dir_all(&bin).unwrap();
+    std::fs::write(bin.join("rustup"), "").unwrap();
+    assert_eq!(find_rustup(home), Some(bin.join("rustup")));
+}
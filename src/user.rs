@@ -0,0 +1,273 @@
+use crate::command::{CommandExt, Escalate};
+use crate::is_env_name;
+use anyhow::{ensure, Context, Result};
+use camino::{Utf8Path, Utf8PathBuf};
+use serde::{Deserialize, Serialize};
+use std::process::Command;
+
+// Run by `BuildUser::wrap_job` as `sh -c LOAD_ENV_FILE sh ENV_FILE PROGRAM ARGS...`.
+const LOAD_ENV_FILE: &str = r#"set -a && . "$1" && rm -f -- "$1" && shift && exec "$@""#;
+
+// A local user to run the job script as, so that it can't read the invoking user's files and
+// permission bugs show up as they would in CI.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct BuildUser {
+    pub(crate) name: String,
+    pub(crate) home: Utf8PathBuf,
+    group: String,
+    uid: libc::uid_t,
+    gid: libc::gid_t,
+    groups: Vec<libc::gid_t>,
+}
+
+impl BuildUser {
+    pub(crate) fn lookup(name: &str) -> Result<BuildUser> {
+        let id = |arg: &str| -> Result<String> {
+            let output = Command::new("id")
+                .arg(arg)
+                .arg(name)
+                .succeed_output()
+                .with_context(|| format!("failed to look up user `{}`", name))?;
+            Ok(std::str::from_utf8(&output.stdout)?.trim().to_owned())
+        };
+        let passwd = Command::new("getent")
+            .args(["passwd", name])
+            .succeed_output()
+            .with_context(|| format!("failed to look up user `{}`", name))?;
+        let home = std::str::from_utf8(&passwd.stdout)?
+            .trim()
+            .split(':')
+            .nth(5)
+            .with_context(|| format!("failed to find home directory of `{}`", name))?
+            .into();
+        Ok(BuildUser {
+            name: name.to_owned(),
+            home,
+            group: id("-gn")?,
+            uid: id("-u")?.parse()?,
+            gid: id("-g")?.parse()?,
+            groups: id("-G")?
+                .split_whitespace()
+                .map(str::parse)
+                .collect::<Result<_, _>>()?,
+        })
+    }
+
+    // The `user:group` to chown the job's files to.
+    pub(crate) fn owner(&self) -> String {
+        format!("{}:{}", self.name, self.group)
+    }
+
+    // Rewrites `command` to run as this user with `escalate`. `sudo` and `doas` don't pass
+    // arbitrary variables through, so the environment set on `command` moves into an `env -i`
+    // invocation, where anyone can see it with `ps`; `wrap_job` is for environments with secrets.
+    // If we're already root, privileges are dropped by `drop_privileges` instead.
+    pub(crate) fn wrap(&self, command: Command, escalate: Escalate) -> Command {
+        let mut argv = vec!["env".to_owned(), "-i".to_owned()];
+        for (key, value) in command.get_envs() {
+            if let Some(value) = value {
+                argv.push(format!(
+                    "{}={}",
+                    key.to_string_lossy(),
+                    value.to_string_lossy()
+                ));
+            }
+        }
+        argv.extend(command.argv());
+        self.wrap_argv(command, escalate, &[], argv)
+    }
+
+    // Like `wrap`, but the environment is read from `env_file` (written by `write_env_file`, and
+    // removed once it's read), and `sudo` and `doas` aren't allowed to ask for a password: the
+    // job runs in its own process group, where a prompt would stop it. `authenticate` first.
+    pub(crate) fn wrap_job(
+        &self,
+        command: Command,
+        escalate: Escalate,
+        env_file: &Utf8Path,
+    ) -> Command {
+        let mut argv = [
+            "env",
+            "-i",
+            "/bin/sh",
+            "-c",
+            LOAD_ENV_FILE,
+            "sh",
+            env_file.as_str(),
+        ]
+        .map(ToOwned::to_owned)
+        .to_vec();
+        argv.extend(command.argv());
+        self.wrap_argv(command, escalate, &["-n"], argv)
+    }
+
+    fn wrap_argv(
+        &self,
+        command: Command,
+        escalate: Escalate,
+        options: &[&str],
+        argv: Vec<String>,
+    ) -> Command {
+        let mut wrapped = match escalate {
+            Escalate::Sudo | Escalate::Doas => {
+                let mut wrapped = Command::new(escalate.to_string());
+                wrapped.args(options).args(["-u", &self.name]).args(argv);
+                wrapped
+            }
+            // pfexec runs commands as root, which can `su` without a password.
+            Escalate::Pfexec => {
+                let mut wrapped = Command::new("pfexec");
+                wrapped
+                    .args(["su", &self.name, "-c"])
+                    .arg(shell_words::join(argv));
+                wrapped
+            }
+            Escalate::None => return command,
+        };
+        if let Some(dir) = command.get_current_dir() {
+            wrapped.current_dir(dir);
+        }
+        wrapped
+    }
+
+    // Asks for a password now, in the foreground, if `wrap_job`'s command will need one.
+    pub(crate) fn authenticate(escalate: Escalate) -> Result<()> {
+        match escalate {
+            Escalate::Sudo => Command::new("sudo").arg("-v").succeed(),
+            Escalate::Doas => Command::new("doas").arg("true").succeed(),
+            Escalate::Pfexec | Escalate::None => Ok(()),
+        }
+    }
+
+    // Writes the environment set on `command` to `path`, readable only by this user, for
+    // `wrap_job`.
+    pub(crate) fn write_env_file(
+        &self,
+        command: &Command,
+        path: &Utf8Path,
+        escalate: Escalate,
+    ) -> Result<()> {
+        use std::io::Write;
+        use std::os::unix::fs::OpenOptionsExt;
+
+        let contents = env_file_contents(command)?;
+        // A file left behind by an earlier run may belong to this user.
+        if path.exists() {
+            escalate.command("rm").arg("-f").arg(path).succeed()?;
+        }
+        std::fs::File::options()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(path)
+            .and_then(|mut file| file.write_all(contents.as_bytes()))
+            .with_context(|| format!("failed to write {}", path))?;
+        escalate
+            .command("chown")
+            .arg(&self.name)
+            .arg(path)
+            .succeed()
+    }
+
+    // With `Escalate::None`, switches to this user just before running `command`. This must be
+    // called after anything else that needs privileges before the exec (like joining a cgroup).
+    pub(crate) fn drop_privileges(&self, command: &mut Command, escalate: Escalate) {
+        use std::os::unix::process::CommandExt;
+
+        if escalate != Escalate::None {
+            return;
+        }
+        let (uid, gid, groups) = (self.uid, self.gid, self.groups.clone());
+        // SAFETY: this only makes system calls using memory allocated before the fork.
+        unsafe {
+            command.pre_exec(move || {
+                #[allow(clippy::cast_possible_truncation, clippy::cast_possible_wrap)]
+                if libc::setgroups(groups.len() as _, groups.as_ptr()) == -1
+                    || libc::setgid(gid) == -1
+                    || libc::setuid(uid) == -1
+                {
+                    return Err(std::io::Error::last_os_error());
+                }
+                Ok(())
+            });
+        }
+    }
+}
+
+// `KEY='VALUE'` lines for the environment set on `command`, for `sh` to source.
+fn env_file_contents(command: &Command) -> Result<String> {
+    use std::fmt::Write;
+
+    let mut contents = String::new();
+    for (key, value) in command.get_envs() {
+        let (key, Some(value)) = (key.to_string_lossy(), value) else {
+            continue;
+        };
+        ensure!(
+            is_env_name(&key),
+            "can't pass variable `{}` to the build user",
+            key
+        );
+        writeln!(
+            contents,
+            "{}={}",
+            key,
+            shell_words::quote(&value.to_string_lossy())
+        )?;
+    }
+    Ok(contents)
+}
+
+#[cfg(test)]
+#[test]
+fn test_wrap_job() {
+    let user = BuildUser {
+        name: "build".into(),
+        home: "/home/build".into(),
+        group: "build".into(),
+        uid: 1001,
+        gid: 1001,
+        groups: vec![1001],
+    };
+    let mut command = Command::new("/bin/bash");
+    command
+        .arg("/tmp/buildomat-at-home-01H3XMET848BWFBC9KFRN1KCWX.sh")
+        .env_clear()
+        .env("GITHUB_TOKEN", "ghp_secret")
+        .env("GREETING", "it's me")
+        .current_dir("/work/oxidecomputer/omicron");
+    assert_eq!(
+        env_file_contents(&command).unwrap(),
+        "GITHUB_TOKEN=ghp_secret\nGREETING='it'\\''s me'\n"
+    );
+
+    let env_file = Utf8Path::new("/tmp/buildomat-at-home-01H3XMET848BWFBC9KFRN1KCWX.env");
+    let wrapped = user.wrap_job(command, Escalate::Sudo, env_file);
+    assert_eq!(
+        wrapped.argv(),
+        [
+            "sudo",
+            "-n",
+            "-u",
+            "build",
+            "env",
+            "-i",
+            "/bin/sh",
+            "-c",
+            LOAD_ENV_FILE,
+            "sh",
+            env_file.as_str(),
+            "/bin/bash",
+            "/tmp/buildomat-at-home-01H3XMET848BWFBC9KFRN1KCWX.sh",
+        ]
+    );
+    assert_eq!(wrapped.get_envs().count(), 0);
+    assert_eq!(
+        wrapped.get_current_dir(),
+        Some(std::path::Path::new("/work/oxidecomputer/omicron"))
+    );
+
+    let mut command = Command::new("env");
+    command.env("MY-VAR", "1");
+    assert!(env_file_contents(&command).is_err());
+}